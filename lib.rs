@@ -25,6 +25,10 @@ mod phala_probe {
             pink::ext()
                 .cache_set(b"sidevm_probing::param::public_key", &public_key.encode())
                 .unwrap();
+            // the sidevm signs its `/resolved` responses with the same key
+            pink::ext()
+                .cache_set(b"sidevm_probing::param::private_key", &private_key.encode())
+                .unwrap();
 
             pink::ext()
                 .cache_set(b"sidevm_probing::param::dim_size", &(3 as u64).encode())
@@ -12,7 +12,7 @@
 #
 #sleep 5;
 
-# assign worker id
+# assign worker id (requires a sideprog.wasm built with `make FEATURES=test-worker-id`)
 curl -d "0" 127.0.0.1:8000/push/message/0
 curl -d "1" 127.0.0.1:8001/push/message/0
 curl -d "2" 127.0.0.1:8002/push/message/0
@@ -20,10 +20,20 @@ curl -d "3" 127.0.0.1:8003/push/message/0
 
 sleep 1;
 
-curl -d '{"command":"add_peer","data":"00000001"}' 127.0.0.1:8000/push/message/0
-curl -d '{"command":"add_peer","data":"00000002"}' 127.0.0.1:8001/push/message/0
-curl -d '{"command":"add_peer","data":"00000000"}' 127.0.0.1:8002/push/message/0
-curl -d '{"command":"add_peer","data":"00000000"}' 127.0.0.1:8003/push/message/0
+# distribute the peer directory
+# (in test mode, the key of worker N is derived from the seed [N; 32])
+DIRECTORY='{"command":"set_directory","data":"{\"def12e42f3e487e9b14095aa8d5cc16a33491f1b50dadcf8811d1480f3fa8627\":[\"127.0.0.1:2000\"],\"189dac29296d31814dc8c56cf3d36a0543372bba7538fa322a4aebfebc39e056\":[\"127.0.0.1:2001\"],\"1a4fee48c1ba1a48e8cd43782a8485d635aa91cfb82cbb477f0c1c576bc4031c\":[\"127.0.0.1:2002\"],\"8ee504148e75c34e8f051899b3c6e4241ff18dc1c9211260b6a6a434bedb485f\":[\"127.0.0.1:2003\"]}"}'
+curl -d "$DIRECTORY" 127.0.0.1:8000/push/message/0
+curl -d "$DIRECTORY" 127.0.0.1:8001/push/message/0
+curl -d "$DIRECTORY" 127.0.0.1:8002/push/message/0
+curl -d "$DIRECTORY" 127.0.0.1:8003/push/message/0
+
+sleep 1;
+
+curl -d '{"command":"add_peer","data":"189dac29296d31814dc8c56cf3d36a0543372bba7538fa322a4aebfebc39e056"}' 127.0.0.1:8000/push/message/0
+curl -d '{"command":"add_peer","data":"1a4fee48c1ba1a48e8cd43782a8485d635aa91cfb82cbb477f0c1c576bc4031c"}' 127.0.0.1:8001/push/message/0
+curl -d '{"command":"add_peer","data":"def12e42f3e487e9b14095aa8d5cc16a33491f1b50dadcf8811d1480f3fa8627"}' 127.0.0.1:8002/push/message/0
+curl -d '{"command":"add_peer","data":"def12e42f3e487e9b14095aa8d5cc16a33491f1b50dadcf8811d1480f3fa8627"}' 127.0.0.1:8003/push/message/0
 
 sleep 1;
 
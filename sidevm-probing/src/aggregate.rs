@@ -0,0 +1,155 @@
+use crate::types::{Aggregator, ProbeParameters};
+use crate::utils::euclidean_distance;
+
+/// Combines several views of the same coordinate into one.
+///
+/// Returns the aggregated coordinate and the number of rejected contributions, i.e. those that had
+/// no influence on the result in any dimension (or had the wrong dimension).
+pub fn aggregate(contributions: &[Vec<f64>], dim: usize, parameters: &ProbeParameters) -> Option<(Vec<f64>, u64)> {
+    let valid = contributions
+        .iter()
+        .filter(|c| c.len() == dim && c.iter().all(|x| x.is_finite()))
+        .cloned()
+        .collect::<Vec<Vec<f64>>>();
+    let malformed = (contributions.len() - valid.len()) as u64;
+    if valid.is_empty() {
+        return None;
+    }
+
+    let (value, rejected) = match parameters.aggregator {
+        Aggregator::Mean => (mean(&valid, dim), 0),
+        Aggregator::Median => trimmed_mean(&valid, dim, (valid.len() - 1) / 2),
+        Aggregator::TrimmedMean => {
+            let trimmed = (valid.len() as f64 * parameters.trim_ratio).floor() as usize;
+            trimmed_mean(&valid, dim, trimmed.min((valid.len() - 1) / 2))
+        }
+        Aggregator::Krum => krum(&valid, dim, parameters.krum_byzantine as usize),
+    };
+
+    Some((value, rejected + malformed))
+}
+
+fn mean(contributions: &[Vec<f64>], dim: usize) -> Vec<f64> {
+    let mut sum = vec![0.0 as f64; dim];
+    for contribution in contributions {
+        for (s, x) in sum.iter_mut().zip(contribution.iter()) {
+            *s += x;
+        }
+    }
+    sum.iter().map(|s| s / contributions.len() as f64).collect()
+}
+
+/// Coordinate-wise mean after dropping the `trimmed` lowest and highest values of every dimension.
+/// With `trimmed = (n - 1) / 2` this is the coordinate-wise median.
+fn trimmed_mean(contributions: &[Vec<f64>], dim: usize, trimmed: usize) -> (Vec<f64>, u64) {
+    let n = contributions.len();
+    let mut used = vec![false; n];
+    let mut value = vec![0.0 as f64; dim];
+
+    for d in 0..dim {
+        let mut order = (0..n).collect::<Vec<usize>>();
+        order.sort_by(|a, b| contributions[*a][d].total_cmp(&contributions[*b][d]));
+        let kept = &order[trimmed..n - trimmed];
+        for i in kept {
+            used[*i] = true;
+            value[d] += contributions[*i][d] / kept.len() as f64;
+        }
+    }
+
+    (value, used.iter().filter(|u| !**u).count() as u64)
+}
+
+/// Multi-Krum: keeps the `n - f` contributions closest to their `n - f - 2` nearest neighbours and
+/// averages them. Falls back to the coordinate-wise median when there are too few contributions.
+fn krum(contributions: &[Vec<f64>], dim: usize, byzantine: usize) -> (Vec<f64>, u64) {
+    let n = contributions.len();
+    if n < 2 * byzantine + 3 {
+        return trimmed_mean(contributions, dim, (n - 1) / 2);
+    }
+
+    let neighbours = n - byzantine - 2;
+    let mut scores = contributions
+        .iter()
+        .enumerate()
+        .map(|(i, a)| {
+            let mut distances = contributions
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .map(|(_, b)| euclidean_distance(a, b).powi(2))
+                .collect::<Vec<f64>>();
+            distances.sort_by(|x, y| x.total_cmp(y));
+            (i, distances.iter().take(neighbours).sum::<f64>())
+        })
+        .collect::<Vec<(usize, f64)>>();
+    scores.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+    let selected = scores
+        .iter()
+        .take(n - byzantine)
+        .map(|(i, _)| contributions[*i].clone())
+        .collect::<Vec<Vec<f64>>>();
+
+    (mean(&selected, dim), byzantine as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parameters(aggregator: Aggregator) -> ProbeParameters {
+        ProbeParameters {
+            aggregator,
+            krum_byzantine: 1,
+            ..Default::default()
+        }
+    }
+
+    // five honest views around (1, 1) and a byzantine one far away
+    fn contributions() -> Vec<Vec<f64>> {
+        vec![
+            vec![1.0, 1.0],
+            vec![1.1, 0.9],
+            vec![0.9, 1.1],
+            vec![1.05, 1.0],
+            vec![0.95, 0.95],
+            vec![100.0, -100.0],
+        ]
+    }
+
+    fn close_to_honest(value: &[f64]) -> bool {
+        euclidean_distance(value, &[1.0, 1.0]) < 0.2
+    }
+
+    #[test]
+    fn mean_is_pulled_by_outliers() {
+        let (value, rejected) = aggregate(&contributions(), 2, &parameters(Aggregator::Mean)).unwrap();
+        assert!(!close_to_honest(&value));
+        assert_eq!(rejected, 0);
+    }
+
+    #[test]
+    fn median_rejects_outliers() {
+        let (value, rejected) = aggregate(&contributions(), 2, &parameters(Aggregator::Median)).unwrap();
+        assert!(close_to_honest(&value));
+        assert!(rejected >= 1);
+    }
+
+    #[test]
+    fn krum_rejects_outliers() {
+        let (value, rejected) = aggregate(&contributions(), 2, &parameters(Aggregator::Krum)).unwrap();
+        assert!(close_to_honest(&value));
+        assert_eq!(rejected, 1);
+    }
+
+    #[test]
+    fn malformed_contributions_are_rejected() {
+        let mut contributions = contributions();
+        contributions.push(vec![1.0]);
+        contributions.push(vec![f64::NAN, 1.0]);
+        let (value, rejected) = aggregate(&contributions, 2, &parameters(Aggregator::Krum)).unwrap();
+        assert!(close_to_honest(&value));
+        assert_eq!(rejected, 3);
+        assert!(aggregate(&[vec![1.0]], 2, &parameters(Aggregator::Median)).is_none());
+    }
+}
@@ -0,0 +1,263 @@
+use nalgebra::DMatrix;
+use std::collections::HashMap;
+
+/// How maps of the same space computed by different nodes may differ while predicting the same
+/// latencies, and therefore how a peer's map is brought into our frame before merging it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlignmentMode {
+    // rotation/reflection and translation, the isometries of Euclidean space
+    Rigid,
+    // rotation/reflection about the origin, which maps a sphere or Poincaré ball centred there onto itself
+    Origin,
+    // no orthogonal map relates two factorisations with non-negative components, maps are merged as is
+    Unaligned,
+}
+
+/// Maps coordinates from a peer's frame into ours: `(x - remote_center) * rotation + local_center`.
+/// Only the leading position components are transformed; trailing ones (e.g. Vivaldi heights) are kept.
+pub struct Alignment {
+    rotation: DMatrix<f64>,
+    remote_center: Vec<f64>,
+    local_center: Vec<f64>,
+    // root mean square distance between the aligned shared points and ours
+    pub residual: f64,
+}
+
+impl Alignment {
+    pub fn apply(&self, value: &[f64]) -> Vec<f64> {
+        let dim = self.local_center.len();
+        if value.len() < dim {
+            return value.to_vec();
+        }
+        let centered = value
+            .iter()
+            .zip(self.remote_center.iter())
+            .map(|(x, c)| x - c)
+            .collect::<Vec<f64>>();
+        (0..dim)
+            .map(|j| {
+                (0..dim).fold(self.local_center[j], |acc, i| acc + centered[i] * self.rotation[(i, j)])
+            })
+            .chain(value[dim..].iter().cloned())
+            .collect()
+    }
+}
+
+/// Aligns `remote` onto `local` as allowed by `mode`. Rigid alignment falls back to a translation when
+/// there are too few shared peers to fix the rotation. `None` if the maps cannot be aligned.
+pub fn align(
+    mode: AlignmentMode,
+    local: &HashMap<String, Vec<f64>>,
+    remote: &HashMap<String, Vec<f64>>,
+    dim: usize,
+) -> Option<Alignment> {
+    match mode {
+        AlignmentMode::Rigid => procrustes(local, remote, dim, true).or_else(|| translation(local, remote, dim)),
+        AlignmentMode::Origin => procrustes(local, remote, dim, false),
+        AlignmentMode::Unaligned => None,
+    }
+}
+
+/// Pairs of (local, remote) coordinates of the peers both maps know.
+fn shared<'a>(
+    local: &'a HashMap<String, Vec<f64>>,
+    remote: &'a HashMap<String, Vec<f64>>,
+    dim: usize,
+) -> Vec<(&'a Vec<f64>, &'a Vec<f64>)> {
+    remote
+        .iter()
+        .filter(|(_, v)| v.len() >= dim)
+        .filter_map(|(k, v)| local.get(k).filter(|l| l.len() == v.len()).map(|l| (l, v)))
+        .collect()
+}
+
+/// Shifts the `remote` map so that the peers both maps know share the same centre, leaving the rotation
+/// as is. Needs a single shared peer.
+pub fn translation(
+    local: &HashMap<String, Vec<f64>>,
+    remote: &HashMap<String, Vec<f64>>,
+    dim: usize,
+) -> Option<Alignment> {
+    let shared = shared(local, remote, dim);
+    if shared.is_empty() {
+        return None;
+    }
+
+    let n = shared.len() as f64;
+    let local_center = (0..dim).map(|j| shared.iter().map(|(l, _)| l[j]).sum::<f64>() / n).collect::<Vec<f64>>();
+    let remote_center = (0..dim).map(|j| shared.iter().map(|(_, r)| r[j]).sum::<f64>() / n).collect::<Vec<f64>>();
+    let residual = (shared
+        .iter()
+        .map(|(l, r)| (0..dim).map(|j| (r[j] - remote_center[j] - l[j] + local_center[j]).powi(2)).sum::<f64>())
+        .sum::<f64>()
+        / n)
+        .sqrt();
+
+    Some(Alignment {
+        rotation: DMatrix::identity(dim, dim),
+        remote_center,
+        local_center,
+        residual,
+    })
+}
+
+/// Orthogonal Procrustes: finds the rotation/reflection, and the translation if `center` is set, that
+/// best maps the `remote` coordinates of the peers both maps know onto our `local` ones, using the first
+/// `dim` components. Needs at least `dim + 1` shared peers, or `dim` without translation.
+pub fn procrustes(
+    local: &HashMap<String, Vec<f64>>,
+    remote: &HashMap<String, Vec<f64>>,
+    dim: usize,
+    center: bool,
+) -> Option<Alignment> {
+    let shared = shared(local, remote, dim);
+    if shared.len() < dim + center as usize {
+        return None;
+    }
+
+    let n = shared.len();
+    let local_points = DMatrix::from_fn(n, dim, |i, j| shared[i].0[j]);
+    let remote_points = DMatrix::from_fn(n, dim, |i, j| shared[i].1[j]);
+    let (local_center, remote_center) = if center {
+        (local_points.row_mean().iter().cloned().collect(), remote_points.row_mean().iter().cloned().collect())
+    } else {
+        (vec![0.0; dim], vec![0.0; dim])
+    };
+
+    let mut local_centered = local_points.clone();
+    let mut remote_centered = remote_points.clone();
+    for i in 0..n {
+        for j in 0..dim {
+            local_centered[(i, j)] -= local_center[j];
+            remote_centered[(i, j)] -= remote_center[j];
+        }
+    }
+
+    // R = U * V^T where U * S * V^T = X^T * Y
+    let svd = (remote_centered.transpose() * &local_centered).svd(true, true);
+    let rotation = svd.u? * svd.v_t?;
+
+    let aligned = &remote_centered * &rotation;
+    let residual = ((0..n)
+        .map(|i| (0..dim).map(|j| (aligned[(i, j)] - local_centered[(i, j)]).powi(2)).sum::<f64>())
+        .sum::<f64>()
+        / n as f64)
+        .sqrt();
+
+    Some(Alignment {
+        rotation,
+        remote_center,
+        local_center,
+        residual,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distance::{DistanceModel, DotProduct, Euclidean, EuclideanHeight, Hyperbolic, Spherical};
+
+    fn local() -> HashMap<String, Vec<f64>> {
+        [
+            ("a", vec![0.0, 0.0, 5.0]),
+            ("b", vec![10.0, 0.0, 1.0]),
+            ("c", vec![0.0, 20.0, 2.0]),
+            ("d", vec![7.0, 3.0, 0.5]),
+        ]
+        .into_iter()
+        .map(|(id, coord)| (id.to_string(), coord))
+        .collect()
+    }
+
+    // our map mirrored, rotated by 30 degrees and shifted, heights untouched
+    fn transformed(local: &HashMap<String, Vec<f64>>) -> HashMap<String, Vec<f64>> {
+        let (sin, cos) = 30f64.to_radians().sin_cos();
+        local
+            .iter()
+            .map(|(id, v)| {
+                let x = -v[0];
+                let y = v[1];
+                (id.clone(), vec![x * cos - y * sin + 3.0, x * sin + y * cos - 4.0, v[2]])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn recovers_rotation_and_reflection() {
+        let local = local();
+        let remote = transformed(&local);
+        let alignment = procrustes(&local, &remote, 2, true).unwrap();
+        assert!(alignment.residual < 1e-9);
+        for (id, value) in remote.iter() {
+            let aligned = alignment.apply(value);
+            for (a, b) in aligned.iter().zip(local[id].iter()) {
+                assert!((a - b).abs() < 1e-9, "{}: {:?} != {:?}", id, aligned, local[id]);
+            }
+        }
+    }
+
+    #[test]
+    fn needs_enough_shared_peers() {
+        let local = local();
+        let remote = transformed(&local)
+            .into_iter()
+            .filter(|(id, _)| id == "a" || id == "b")
+            .collect::<HashMap<String, Vec<f64>>>();
+        assert!(procrustes(&local, &remote, 2, true).is_none());
+    }
+
+    fn assert_aligned(alignment: &Alignment, local: &HashMap<String, Vec<f64>>, remote: &HashMap<String, Vec<f64>>) {
+        for (id, value) in remote.iter() {
+            let aligned = alignment.apply(value);
+            for (a, b) in aligned.iter().zip(local[id].iter()) {
+                assert!((a - b).abs() < 1e-9, "{}: {:?} != {:?}", id, aligned, local[id]);
+            }
+        }
+    }
+
+    #[test]
+    fn falls_back_to_a_translation() {
+        let local = local();
+        let remote = local
+            .iter()
+            .filter(|(id, _)| *id == "a" || *id == "b")
+            .map(|(id, v)| (id.clone(), vec![v[0] + 3.0, v[1] - 4.0, v[2]]))
+            .collect::<HashMap<String, Vec<f64>>>();
+        assert!(procrustes(&local, &remote, 2, true).is_none());
+        let alignment = align(AlignmentMode::Rigid, &local, &remote, 2).unwrap();
+        assert!(alignment.residual < 1e-9);
+        assert_aligned(&alignment, &local, &remote);
+
+        assert!(align(AlignmentMode::Rigid, &local, &HashMap::new(), 2).is_none());
+    }
+
+    #[test]
+    fn aligns_about_the_origin() {
+        // points on a sphere, rotated by 90 degrees about the z axis
+        let local = [("a", vec![1.0, 0.0, 0.0]), ("b", vec![0.0, 1.0, 0.0]), ("c", vec![0.6, 0.0, 0.8])]
+            .into_iter()
+            .map(|(id, coord)| (id.to_string(), coord))
+            .collect::<HashMap<String, Vec<f64>>>();
+        let remote = local
+            .iter()
+            .map(|(id, v)| (id.clone(), vec![-v[1], v[0], v[2]]))
+            .collect::<HashMap<String, Vec<f64>>>();
+        let alignment = align(Spherical { radius: 1.0 }.alignment(), &local, &remote, 3).unwrap();
+        assert!(alignment.residual < 1e-9);
+        assert_aligned(&alignment, &local, &remote);
+        // the origin is kept, so the aligned points stay on the sphere
+        assert_eq!(alignment.apply(&[0.0, 0.0, 0.0]), vec![0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn alignment_modes_of_the_models() {
+        assert_eq!(Euclidean.alignment(), AlignmentMode::Rigid);
+        assert_eq!(EuclideanHeight.alignment(), AlignmentMode::Rigid);
+        assert_eq!(Spherical { radius: 1.0 }.alignment(), AlignmentMode::Origin);
+        assert_eq!(Hyperbolic { scale: 1.0 }.alignment(), AlignmentMode::Origin);
+        // factorisations are merged without alignment on purpose
+        assert_eq!(DotProduct.alignment(), AlignmentMode::Unaligned);
+        let local = local();
+        assert!(align(DotProduct.alignment(), &local, &transformed(&local), 2).is_none());
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::VecDeque;
+
+use crate::types::{ClockEstimate, ClockSample};
+use crate::utils::percentile;
+
+impl ClockSample {
+    /// NTP-style sample from the four timestamps of an echo, in microseconds: our send time, the
+    /// peer's receive and reply times, and our receive time.
+    pub fn new(sent_at: u64, received_at: u64, replied_at: u64, returned_at: u64) -> Self {
+        let forward_ms = (received_at as i64 - sent_at as i64) as f64 / 1000.0;
+        let backward_ms = (returned_at as i64 - replied_at as i64) as f64 / 1000.0;
+        ClockSample {
+            offset_ms: (forward_ms - backward_ms) / 2.0,
+            delay_ms: forward_ms + backward_ms,
+            forward_ms,
+            backward_ms,
+        }
+    }
+}
+
+/// Takes the offset of the sample with the smallest round-trip delay, which suffered the least
+/// queueing, and uses it to correct the median raw one-way delays.
+pub fn estimate(samples: &VecDeque<ClockSample>, updated_at: u64) -> Option<ClockEstimate> {
+    let best = samples.iter().min_by(|a, b| a.delay_ms.total_cmp(&b.delay_ms))?;
+    let forward = samples.iter().map(|s| s.forward_ms).collect::<Vec<f64>>();
+    let backward = samples.iter().map(|s| s.backward_ms).collect::<Vec<f64>>();
+
+    Some(ClockEstimate {
+        offset_ms: best.offset_ms,
+        delay_ms: best.delay_ms,
+        forward_ms: (percentile(&forward, 0.5) - best.offset_ms).max(0.0),
+        backward_ms: (percentile(&backward, 0.5) + best.offset_ms).max(0.0),
+        samples: samples.len() as u64,
+        updated_at,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the peer's clock is 5ms ahead and the link takes 10ms each way, plus `queueing_ms` forward
+    fn sample(queueing_ms: u64) -> ClockSample {
+        let sent_at = 1_000_000;
+        let received_at = sent_at + (10 + 5 + queueing_ms) * 1000;
+        let replied_at = received_at + 1000;
+        let returned_at = replied_at - 5000 + 10 * 1000;
+        ClockSample::new(sent_at, received_at, replied_at, returned_at)
+    }
+
+    #[test]
+    fn sample_from_timestamps() {
+        let sample = sample(0);
+        assert_eq!(sample.forward_ms, 15.0);
+        assert_eq!(sample.backward_ms, 5.0);
+        assert_eq!(sample.offset_ms, 5.0);
+        assert_eq!(sample.delay_ms, 20.0);
+    }
+
+    #[test]
+    fn estimate_uses_the_lowest_delay_sample() {
+        let samples = vec![sample(30), sample(0), sample(0)].into_iter().collect::<VecDeque<ClockSample>>();
+        let estimate = estimate(&samples, 42).unwrap();
+        assert_eq!(estimate.offset_ms, 5.0);
+        assert_eq!(estimate.delay_ms, 20.0);
+        assert_eq!(estimate.forward_ms, 10.0);
+        assert_eq!(estimate.backward_ms, 10.0);
+        assert_eq!(estimate.samples, 3);
+        assert_eq!(estimate.updated_at, 42);
+    }
+
+    #[test]
+    fn no_estimate_without_samples() {
+        assert!(estimate(&VecDeque::new(), 0).is_none());
+    }
+}
@@ -0,0 +1,246 @@
+use log::info;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::directory::{Directory, PeerDirectory};
+use crate::error::{initialized, initialized_mut, to_json, Error};
+use crate::probe::Probe;
+use crate::types::{
+    BandwidthStats, ClockEstimate, Estimate, EstimateBatchEntry, EstimateBatchRequest, HistorySample, HostDirectoryEntry,
+    NearestRequest, Neighbor, ProbeStatus, QueryBestEndpointRequest, QueryClockOffsetRequest, QueryEstimateRequest,
+    QueryHistoryRequest, SignedResolved,
+};
+use crate::AppState;
+
+// Every operation of the node, whichever channel it comes from: the HTTP API, pink queries (JSON or
+// SCALE `protocol::Request`) and host messages are adapters around `dispatch`.
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "command", content = "data", rename_all = "snake_case")]
+pub enum Command {
+    Echo(Vec<u8>),
+    Resolved,
+    Estimate { from: String, to: String },
+    EstimateBandwidth { from: String, to: String },
+    EstimateBatch(EstimateBatchRequest),
+    BestEndpoint { to: String },
+    History {
+        peer: String,
+        #[serde(default)]
+        since: u64,
+    },
+    ClockOffset { peer: String },
+    Status,
+    AddPeer { id: String },
+    StartOptimize,
+    StopOptimize,
+    SetDirectory { entries: HashMap<String, Vec<String>> },
+    UpdatePeerEndpoints { id: String, endpoints: Vec<String> },
+    RemovePeerEndpoints { id: String },
+    SaveApp,
+    LoadApp,
+    Nearest(NearestRequest),
+}
+
+#[derive(Debug, Clone)]
+pub enum Response {
+    Echo(Vec<u8>),
+    Resolved(SignedResolved),
+    Estimate(Estimate),
+    EstimateBandwidth(BandwidthStats),
+    EstimateBatch(Vec<EstimateBatchEntry>),
+    BestEndpoint(String),
+    History(Vec<HistorySample>),
+    ClockOffset(ClockEstimate),
+    Status(ProbeStatus),
+    // the command changed the node and has nothing to return
+    Done,
+    Nearest(Vec<Neighbor>),
+}
+
+fn parse<T: DeserializeOwned>(data: &str) -> Result<T, Error> {
+    serde_json::from_str(data).map_err(|err| Error::BadRequest(err.to_string()))
+}
+
+impl Command {
+    /// Reads the `{ command, data }` messages of JSON queries and host messages, where `data` is
+    /// either a plain string or a nested JSON document depending on the command.
+    pub fn from_message(command: &str, data: &str) -> Result<Command, Error> {
+        Ok(match command {
+            "echo" => Command::Echo(data.as_bytes().to_vec()),
+            "resolved" => Command::Resolved,
+            "estimate" => {
+                let request: QueryEstimateRequest = parse(data)?;
+                Command::Estimate { from: request.from, to: request.to }
+            }
+            "estimate_bandwidth" => {
+                let request: QueryEstimateRequest = parse(data)?;
+                Command::EstimateBandwidth { from: request.from, to: request.to }
+            }
+            "estimate_batch" => Command::EstimateBatch(parse(data)?),
+            "best_endpoint" => {
+                let request: QueryBestEndpointRequest = parse(data)?;
+                Command::BestEndpoint { to: request.to }
+            }
+            "history" => {
+                let request: QueryHistoryRequest = parse(data)?;
+                Command::History { peer: request.peer, since: request.since }
+            }
+            "clock_offset" => {
+                let request: QueryClockOffsetRequest = parse(data)?;
+                Command::ClockOffset { peer: request.peer }
+            }
+            "status" => Command::Status,
+            "add_peer" => Command::AddPeer { id: data.to_string() },
+            "start_optimize" => Command::StartOptimize,
+            "stop_optimize" => Command::StopOptimize,
+            "set_directory" => Command::SetDirectory { entries: parse(data)? },
+            "update_peer_endpoints" => {
+                let entry: HostDirectoryEntry = parse(data)?;
+                Command::UpdatePeerEndpoints { id: entry.id, endpoints: entry.endpoints }
+            }
+            "remove_peer_endpoints" => Command::RemovePeerEndpoints { id: data.to_string() },
+            "save_app" => Command::SaveApp,
+            "load_app" => Command::LoadApp,
+            "nearest" => Command::Nearest(parse(data)?),
+            command => return Err(Error::BadRequest(format!("unknown command {}", command))),
+        })
+    }
+
+    /// Whether the command leaves the node unchanged.
+    pub fn is_read_only(&self) -> bool {
+        match self {
+            Command::Echo(_)
+            | Command::Resolved
+            | Command::Estimate { .. }
+            | Command::EstimateBandwidth { .. }
+            | Command::EstimateBatch(_)
+            | Command::BestEndpoint { .. }
+            | Command::History { .. }
+            | Command::ClockOffset { .. }
+            | Command::Status
+            | Command::Nearest(_) => true,
+            Command::AddPeer { .. }
+            | Command::StartOptimize
+            | Command::StopOptimize
+            | Command::SetDirectory { .. }
+            | Command::UpdatePeerEndpoints { .. }
+            | Command::RemovePeerEndpoints { .. }
+            | Command::SaveApp
+            | Command::LoadApp => false,
+        }
+    }
+
+    /// Lets only read-only commands through. Channels that cannot tell who sent a command, `POST
+    /// /command` and pink queries, go through this; commands that change the node are reserved to
+    /// host messages.
+    pub fn read_only(self) -> Result<Command, Error> {
+        if self.is_read_only() {
+            Ok(self)
+        } else {
+            Err(Error::Forbidden("commands that change the node are only accepted from host messages".to_string()))
+        }
+    }
+}
+
+impl Response {
+    /// Body of the response over HTTP and JSON queries. Echoes and endpoints are plain text.
+    pub fn into_body(self) -> Result<Vec<u8>, Error> {
+        Ok(match self {
+            Response::Echo(data) => data,
+            Response::Resolved(resolved) => to_json(&resolved)?.into_bytes(),
+            Response::Estimate(estimate) => to_json(&estimate)?.into_bytes(),
+            Response::EstimateBandwidth(stats) => to_json(&stats)?.into_bytes(),
+            Response::EstimateBatch(entries) => to_json(&entries)?.into_bytes(),
+            Response::BestEndpoint(endpoint) => endpoint.into_bytes(),
+            Response::History(history) => to_json(&history)?.into_bytes(),
+            Response::ClockOffset(clock) => to_json(&clock)?.into_bytes(),
+            Response::Status(status) => to_json(&status)?.into_bytes(),
+            Response::Done => Vec::new(),
+            Response::Nearest(neighbors) => to_json(&neighbors)?.into_bytes(),
+        })
+    }
+}
+
+fn save_app(probe: &Probe) -> Result<(), Error> {
+    let probe_state = to_json(probe)?;
+    sidevm::ocall::local_cache_set(b"sidevm_probing::probe_state", probe_state.as_bytes())
+        .map_err(|err| Error::Internal(format!("failed to save the probe state: {:?}", err)))
+}
+
+fn load_app() -> Result<Probe, Error> {
+    let probe_state = sidevm::ocall::local_cache_get(b"sidevm_probing::probe_state")
+        .map_err(|err| Error::Internal(format!("failed to read the probe state: {:?}", err)))?
+        .ok_or(Error::NotAvailable("probe state not found in local cache".to_string()))?;
+    serde_json::from_slice(&probe_state).map_err(|err| Error::Internal(err.to_string()))
+}
+
+pub async fn dispatch(app_state: &AppState, command: Command) -> Result<Response, Error> {
+    info!("Dispatching command: {:?}", command);
+    let mut lock = app_state.lock().await;
+    match command {
+        Command::Echo(data) => Ok(Response::Echo(data)),
+        Command::Resolved => {
+            let resolved = initialized(&lock)?
+                .signed_resolved()
+                .map_err(|err| Error::Internal(err.to_string()))?;
+            Ok(Response::Resolved(resolved))
+        }
+        Command::Estimate { from, to } => Ok(Response::Estimate(initialized(&lock)?.estimate(from, to)?)),
+        Command::EstimateBandwidth { from, to } => {
+            Ok(Response::EstimateBandwidth(initialized(&lock)?.estimate_bandwidth(from, to)?))
+        }
+        Command::EstimateBatch(request) => Ok(Response::EstimateBatch(initialized(&lock)?.estimate_batch(request)?)),
+        Command::BestEndpoint { to } => Ok(Response::BestEndpoint(initialized(&lock)?.get_best_endpoint_to(to)?)),
+        Command::History { peer, since } => Ok(Response::History(initialized(&lock)?.history_since(&peer, since)?)),
+        Command::ClockOffset { peer } => Ok(Response::ClockOffset(initialized(&lock)?.clock_estimate(&peer)?)),
+        Command::Status => Ok(Response::Status(initialized(&lock)?.status.clone())),
+        Command::AddPeer { id } => {
+            initialized_mut(&mut lock)?.add_pending_peer(id);
+            Ok(Response::Done)
+        }
+        Command::StartOptimize => {
+            initialized_mut(&mut lock)?.start_optimize();
+            Ok(Response::Done)
+        }
+        Command::StopOptimize => {
+            initialized_mut(&mut lock)?.stop_optimize();
+            Ok(Response::Done)
+        }
+        Command::SetDirectory { entries } => {
+            initialized_mut(&mut lock)?.directory = Directory::from_entries(entries);
+            Ok(Response::Done)
+        }
+        Command::UpdatePeerEndpoints { id, endpoints } => {
+            initialized_mut(&mut lock)?
+                .directory
+                .upsert(id, endpoints)
+                .map_err(|err| Error::BadRequest(err.to_string()))?;
+            Ok(Response::Done)
+        }
+        Command::RemovePeerEndpoints { id } => {
+            initialized_mut(&mut lock)?
+                .directory
+                .remove(&id)
+                .map_err(|err| Error::BadRequest(err.to_string()))?;
+            Ok(Response::Done)
+        }
+        Command::SaveApp => {
+            save_app(initialized(&lock)?)?;
+            Ok(Response::Done)
+        }
+        Command::LoadApp => {
+            let mut probe = load_app()?;
+            // states saved before nodes knew their own endpoints keep the running ones
+            if probe.endpoints.is_empty() {
+                if let Some(running) = lock.as_ref() {
+                    probe.endpoints = running.endpoints.clone();
+                }
+            }
+            *lock = Some(probe);
+            Ok(Response::Done)
+        }
+        Command::Nearest(request) => Ok(Response::Nearest(initialized(&lock)?.nearest(request)?)),
+    }
+}
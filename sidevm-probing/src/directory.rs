@@ -0,0 +1,129 @@
+use anyhow::{anyhow, Result};
+use log::info;
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils::cache_get;
+
+/// Resolves a peer (identified by its hex-encoded public key) to the endpoints it listens on.
+pub trait PeerDirectory {
+    fn lookup(&self, encoded_public_key: &str) -> Result<Vec<String>>;
+
+    fn upsert(&mut self, encoded_public_key: String, endpoints: Vec<String>) -> Result<()>;
+
+    fn remove(&mut self, encoded_public_key: &str) -> Result<()>;
+}
+
+/// A fixed table of endpoints, loaded once from the local cache.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct StaticDirectory {
+    pub entries: HashMap<String, Vec<String>>,
+}
+
+impl PeerDirectory for StaticDirectory {
+    fn lookup(&self, encoded_public_key: &str) -> Result<Vec<String>> {
+        match self.entries.get(encoded_public_key) {
+            Some(endpoints) if !endpoints.is_empty() => Ok(endpoints.clone()),
+            _ => Err(anyhow!("Peer {} is not in the directory", encoded_public_key)),
+        }
+    }
+
+    fn upsert(&mut self, _encoded_public_key: String, _endpoints: Vec<String>) -> Result<()> {
+        Err(anyhow!("Static directory is read-only"))
+    }
+
+    fn remove(&mut self, _encoded_public_key: &str) -> Result<()> {
+        Err(anyhow!("Static directory is read-only"))
+    }
+}
+
+/// A table fed by the contract through host messages, either as a whole with `set_directory` or entry
+/// by entry.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HostPushedDirectory {
+    pub entries: HashMap<String, Vec<String>>,
+}
+
+impl PeerDirectory for HostPushedDirectory {
+    fn lookup(&self, encoded_public_key: &str) -> Result<Vec<String>> {
+        match self.entries.get(encoded_public_key) {
+            Some(endpoints) if !endpoints.is_empty() => Ok(endpoints.clone()),
+            _ => Err(anyhow!("Peer {} has not been pushed by the host", encoded_public_key)),
+        }
+    }
+
+    fn upsert(&mut self, encoded_public_key: String, endpoints: Vec<String>) -> Result<()> {
+        if endpoints.is_empty() {
+            return Err(anyhow!("Peer {} has no endpoints", &encoded_public_key));
+        }
+        self.entries.insert(encoded_public_key, endpoints);
+        Ok(())
+    }
+
+    fn remove(&mut self, encoded_public_key: &str) -> Result<()> {
+        self.entries.remove(encoded_public_key);
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Directory {
+    Static(StaticDirectory),
+    HostPushed(HostPushedDirectory),
+}
+
+impl Directory {
+    /// Loads the static table from `sidevm_probing::param::directory` if the contract provided one,
+    /// otherwise starts with an empty host-pushed directory.
+    pub fn load() -> Directory {
+        // SCALE-encoded list of (raw public key, endpoints)
+        if let Some(entries) =
+            cache_get::<Vec<(Vec<u8>, Vec<String>)>>(b"sidevm_probing::param::directory")
+        {
+            info!("Loaded static directory with {} entries", entries.len());
+            return Directory::Static(StaticDirectory {
+                entries: entries
+                    .into_iter()
+                    .map(|(public_key, endpoints)| (hex::encode(public_key), endpoints))
+                    .collect(),
+            });
+        }
+
+        Directory::default()
+    }
+
+    /// A host-pushed directory starting with `entries`, which the host can still update afterwards.
+    pub fn from_entries(entries: HashMap<String, Vec<String>>) -> Directory {
+        Directory::HostPushed(HostPushedDirectory { entries })
+    }
+}
+
+impl Default for Directory {
+    fn default() -> Self {
+        Directory::HostPushed(HostPushedDirectory::default())
+    }
+}
+
+impl PeerDirectory for Directory {
+    fn lookup(&self, encoded_public_key: &str) -> Result<Vec<String>> {
+        match self {
+            Directory::Static(directory) => directory.lookup(encoded_public_key),
+            Directory::HostPushed(directory) => directory.lookup(encoded_public_key),
+        }
+    }
+
+    fn upsert(&mut self, encoded_public_key: String, endpoints: Vec<String>) -> Result<()> {
+        match self {
+            Directory::Static(directory) => directory.upsert(encoded_public_key, endpoints),
+            Directory::HostPushed(directory) => directory.upsert(encoded_public_key, endpoints),
+        }
+    }
+
+    fn remove(&mut self, encoded_public_key: &str) -> Result<()> {
+        match self {
+            Directory::Static(directory) => directory.remove(encoded_public_key),
+            Directory::HostPushed(directory) => directory.remove(encoded_public_key),
+        }
+    }
+}
@@ -0,0 +1,305 @@
+use crate::align::AlignmentMode;
+use crate::utils::euclidean_distance;
+
+const EPS: f64 = 1e-9;
+
+/// The space coordinates live in: how a pair of coordinates translates into a predicted latency.
+pub trait DistanceModel: Send + Sync {
+    /// Length of a coordinate for `dim` spatial dimensions.
+    fn vector_len(&self, dim: usize) -> usize {
+        dim
+    }
+
+    fn distance(&self, a: &[f64], b: &[f64]) -> f64;
+
+    /// Gradient of `distance(a, b)` with respect to `a`.
+    fn gradient(&self, a: &[f64], b: &[f64]) -> Vec<f64>;
+
+    /// Maps a coordinate back into the valid region of the space after an update.
+    fn project(&self, _a: &mut Vec<f64>) {}
+
+    /// Factor applied to a gradient step taken at `a`, for spaces whose coordinates are not in ms.
+    fn step_scale(&self, _a: &[f64]) -> f64 {
+        1.0
+    }
+
+    /// Whether translating every coordinate preserves distances, which re-centring relies on.
+    fn translation_invariant(&self) -> bool {
+        false
+    }
+
+    /// How peers' maps are brought into our frame before merging them.
+    fn alignment(&self) -> AlignmentMode {
+        AlignmentMode::Unaligned
+    }
+
+    /// Whether `distance` is symmetric and obeys the triangle inequality, which the nearest peers index
+    /// relies on to prune the search.
+    fn is_metric(&self) -> bool {
+        true
+    }
+}
+
+pub struct Euclidean;
+
+impl DistanceModel for Euclidean {
+    fn distance(&self, a: &[f64], b: &[f64]) -> f64 {
+        euclidean_distance(a, b)
+    }
+
+    fn gradient(&self, a: &[f64], b: &[f64]) -> Vec<f64> {
+        let norm = euclidean_distance(a, b);
+        a.iter().zip(b.iter()).map(|(i, j)| (i - j) / (norm + EPS)).collect()
+    }
+
+    fn translation_invariant(&self) -> bool {
+        true
+    }
+
+    fn alignment(&self) -> AlignmentMode {
+        AlignmentMode::Rigid
+    }
+}
+
+/// Euclidean positions plus a non-negative height modelling the access link: `[position..., height]`.
+pub struct EuclideanHeight;
+
+impl EuclideanHeight {
+    fn split<'a>(&self, a: &'a [f64]) -> (&'a [f64], f64) {
+        let (position, height) = a.split_at(a.len().saturating_sub(1));
+        (position, height.first().cloned().unwrap_or(0.0))
+    }
+}
+
+impl DistanceModel for EuclideanHeight {
+    fn vector_len(&self, dim: usize) -> usize {
+        dim + 1
+    }
+
+    fn distance(&self, a: &[f64], b: &[f64]) -> f64 {
+        let (position_a, height_a) = self.split(a);
+        let (position_b, height_b) = self.split(b);
+        euclidean_distance(position_a, position_b) + height_a + height_b
+    }
+
+    fn gradient(&self, a: &[f64], b: &[f64]) -> Vec<f64> {
+        let (position_a, _) = self.split(a);
+        let (position_b, _) = self.split(b);
+        let mut gradient = Euclidean.gradient(position_a, position_b);
+        gradient.push(1.0);
+        gradient
+    }
+
+    fn project(&self, a: &mut Vec<f64>) {
+        if let Some(height) = a.last_mut() {
+            *height = height.max(0.0);
+        }
+    }
+
+    fn translation_invariant(&self) -> bool {
+        true
+    }
+
+    fn alignment(&self) -> AlignmentMode {
+        AlignmentMode::Rigid
+    }
+}
+
+/// Points on a sphere of `radius` (in ms) centred at the origin; the distance is the great-circle distance.
+pub struct Spherical {
+    pub radius: f64,
+}
+
+impl Spherical {
+    fn cosine(&self, a: &[f64], b: &[f64]) -> (f64, f64, f64) {
+        let norm_a = a.iter().map(|x| x.powi(2)).sum::<f64>().sqrt() + EPS;
+        let norm_b = b.iter().map(|x| x.powi(2)).sum::<f64>().sqrt() + EPS;
+        let dot = a.iter().zip(b.iter()).map(|(i, j)| i * j).sum::<f64>();
+        ((dot / (norm_a * norm_b)).clamp(-1.0, 1.0), norm_a, norm_b)
+    }
+}
+
+impl DistanceModel for Spherical {
+    fn distance(&self, a: &[f64], b: &[f64]) -> f64 {
+        let (cosine, _, _) = self.cosine(a, b);
+        self.radius * cosine.acos()
+    }
+
+    fn gradient(&self, a: &[f64], b: &[f64]) -> Vec<f64> {
+        let (cosine, norm_a, norm_b) = self.cosine(a, b);
+        // d acos(c) / dc, bounded away from the poles
+        let scale = -self.radius / (1.0 - cosine.powi(2)).max(EPS).sqrt();
+        a.iter()
+            .zip(b.iter())
+            .map(|(i, j)| scale * (j / (norm_a * norm_b) - cosine * i / norm_a.powi(2)))
+            .collect()
+    }
+
+    fn project(&self, a: &mut Vec<f64>) {
+        let norm = a.iter().map(|x| x.powi(2)).sum::<f64>().sqrt();
+        if norm > EPS {
+            for x in a.iter_mut() {
+                *x *= self.radius / norm;
+            }
+        }
+    }
+
+    fn alignment(&self) -> AlignmentMode {
+        AlignmentMode::Origin
+    }
+}
+
+/// Poincaré ball model of hyperbolic space, with distances multiplied by `scale` (in ms).
+pub struct Hyperbolic {
+    pub scale: f64,
+}
+
+impl Hyperbolic {
+    // keep points strictly inside the unit ball
+    const MAX_NORM: f64 = 1.0 - 1e-5;
+}
+
+impl DistanceModel for Hyperbolic {
+    fn distance(&self, a: &[f64], b: &[f64]) -> f64 {
+        let alpha = (1.0 - a.iter().map(|x| x.powi(2)).sum::<f64>()).max(EPS);
+        let beta = (1.0 - b.iter().map(|x| x.powi(2)).sum::<f64>()).max(EPS);
+        let delta = euclidean_distance(a, b).powi(2);
+        self.scale * (1.0 + 2.0 * delta / (alpha * beta)).acosh()
+    }
+
+    fn gradient(&self, a: &[f64], b: &[f64]) -> Vec<f64> {
+        let alpha = (1.0 - a.iter().map(|x| x.powi(2)).sum::<f64>()).max(EPS);
+        let beta = (1.0 - b.iter().map(|x| x.powi(2)).sum::<f64>()).max(EPS);
+        let delta = euclidean_distance(a, b).powi(2);
+        let gamma = 1.0 + 2.0 * delta / (alpha * beta);
+        // d acosh(g) / dg
+        let scale = self.scale / (gamma.powi(2) - 1.0).max(EPS).sqrt();
+        a.iter()
+            .zip(b.iter())
+            .map(|(i, j)| {
+                scale * (4.0 * (i - j) / (alpha * beta) + 4.0 * delta * i / (alpha.powi(2) * beta))
+            })
+            .collect()
+    }
+
+    fn project(&self, a: &mut Vec<f64>) {
+        let norm = a.iter().map(|x| x.powi(2)).sum::<f64>().sqrt();
+        if norm > Self::MAX_NORM {
+            for x in a.iter_mut() {
+                *x *= Self::MAX_NORM / norm;
+            }
+        }
+    }
+
+    // Coordinates live in the unit ball while gradients are in ms, so steps are brought back to the
+    // ball's units, and shrunk towards its boundary where a unit of length covers ever more distance
+    // (the inverse of the Poincaré metric). Otherwise the default `lr` throws points onto the boundary.
+    fn step_scale(&self, a: &[f64]) -> f64 {
+        let alpha = (1.0 - a.iter().map(|x| x.powi(2)).sum::<f64>()).max(EPS);
+        alpha.powi(2) / 4.0 / self.scale.powi(2)
+    }
+
+    fn alignment(&self) -> AlignmentMode {
+        AlignmentMode::Origin
+    }
+}
+
+/// Asymmetric matrix factorisation: coordinates are `[outgoing..., incoming...]` and the predicted
+/// latency from `a` to `b` is `out(a) . in(b)`. Components are kept non-negative.
+pub struct DotProduct;
+
+impl DotProduct {
+    pub fn split<'a>(&self, a: &'a [f64]) -> (&'a [f64], &'a [f64]) {
+        a.split_at(a.len() / 2)
+    }
+}
+
+impl DistanceModel for DotProduct {
+    fn vector_len(&self, dim: usize) -> usize {
+        2 * dim
+    }
+
+    fn distance(&self, a: &[f64], b: &[f64]) -> f64 {
+        let (outgoing, _) = self.split(a);
+        let (_, incoming) = self.split(b);
+        outgoing.iter().zip(incoming.iter()).map(|(i, j)| i * j).sum()
+    }
+
+    fn gradient(&self, a: &[f64], b: &[f64]) -> Vec<f64> {
+        let (_, incoming) = self.split(b);
+        incoming
+            .iter()
+            .cloned()
+            .chain(std::iter::repeat(0.0).take(a.len() - a.len() / 2))
+            .collect()
+    }
+
+    fn project(&self, a: &mut Vec<f64>) {
+        for x in a.iter_mut() {
+            *x = x.max(0.0);
+        }
+    }
+
+    fn is_metric(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // central differences of `distance` with respect to `a`
+    fn numerical_gradient(model: &dyn DistanceModel, a: &[f64], b: &[f64]) -> Vec<f64> {
+        let h = 1e-6;
+        (0..a.len())
+            .map(|i| {
+                let mut plus = a.to_vec();
+                let mut minus = a.to_vec();
+                plus[i] += h;
+                minus[i] -= h;
+                (model.distance(&plus, b) - model.distance(&minus, b)) / (2.0 * h)
+            })
+            .collect()
+    }
+
+    fn assert_gradient(model: &dyn DistanceModel, a: &[f64], b: &[f64]) {
+        let expected = numerical_gradient(model, a, b);
+        let gradient = model.gradient(a, b);
+        for (x, y) in gradient.iter().zip(expected.iter()) {
+            assert!((x - y).abs() <= 1e-4 * y.abs().max(1.0), "{:?} != {:?}", gradient, expected);
+        }
+    }
+
+    #[test]
+    fn gradients_match_finite_differences() {
+        assert_gradient(&Euclidean, &[1.0, 2.0, -3.0], &[-4.0, 0.5, 2.0]);
+        assert_gradient(&EuclideanHeight, &[1.0, 2.0, -3.0, 4.0], &[-4.0, 0.5, 2.0, 1.0]);
+        let spherical = Spherical { radius: 100.0 };
+        assert_gradient(&spherical, &[60.0, 50.0, -62.0], &[-30.0, 90.0, 30.0]);
+        assert_gradient(&spherical, &[1.0, 2.0, 2.0], &[2.0, -1.0, 0.5]);
+        let hyperbolic = Hyperbolic { scale: 50.0 };
+        assert_gradient(&hyperbolic, &[0.1, 0.2, -0.3], &[-0.4, 0.1, 0.2]);
+        assert_gradient(&hyperbolic, &[0.8, 0.1, 0.3], &[0.0, -0.5, 0.1]);
+    }
+
+    #[test]
+    fn hyperbolic_steps_stay_inside_the_ball() {
+        let model = Hyperbolic { scale: 50.0 };
+        let (a, b) = ([0.1, 0.0, 0.0], [-0.5, 0.0, 0.0]);
+        // a 20 ms error at the default learning rate of 1, as `gradient_descent` would apply it
+        let error = 20.0;
+        let step = model.step_scale(&a);
+        let mut moved = a
+            .iter()
+            .zip(model.gradient(&a, &b).iter())
+            .map(|(x, g)| x + g * error * step)
+            .collect::<Vec<f64>>();
+        model.project(&mut moved);
+        let norm = moved.iter().map(|x| x.powi(2)).sum::<f64>().sqrt();
+        assert!(norm < 0.5, "{}", norm);
+        // the step covers about the error, like in the other spaces
+        let moved_by = model.distance(&moved, &b) - model.distance(&a, &b);
+        assert!((moved_by - error).abs() < 0.25 * error, "{}", moved_by);
+    }
+}
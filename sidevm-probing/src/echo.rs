@@ -0,0 +1,234 @@
+use anyhow::{anyhow, Result};
+use log::{error, info, warn};
+use scale::{Decode, Encode};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::types::{Capabilities, ClockSample, ProbeParameters};
+use crate::utils::{now_us, with_timeout};
+
+// Minimal latency probe protocol, served next to the HTTP API: every frame is a big-endian u32
+// length followed by a SCALE-encoded `EchoRequest` or `EchoReply`. A connection carries any number
+// of request/reply rounds so that connection setup is not part of the measured RTT.
+
+const MAX_FRAME_LEN: u32 = 256;
+
+#[derive(Encode, Decode, Debug, Clone)]
+struct EchoRequest {
+    // sender's wall clock, in microseconds
+    sent_at: u64,
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+struct EchoReply {
+    sent_at: u64,
+    // responder's wall clock when the request was read and when the reply was written
+    received_at: u64,
+    replied_at: u64,
+}
+
+/// A successful echo: the RTT measured with a monotonic clock and, when the responder reported its
+/// timestamps, the clock comparison.
+pub struct EchoSample {
+    pub rtt_ms: f64,
+    pub clock: Option<ClockSample>,
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let len = reader.read_u32().await?;
+    if len > MAX_FRAME_LEN {
+        return Err(anyhow!("Echo frame of {} bytes is too large", len));
+    }
+    let mut buf = vec![0u8; len as usize];
+    reader.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> Result<()> {
+    writer.write_u32(payload.len() as u32).await?;
+    writer.write_all(payload).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// The echo address matching an `host:port` HTTP endpoint, or `None` if the endpoint has no port or
+/// the offset is 0 (disabled).
+pub fn address(endpoint: &str, port_offset: u16) -> Option<String> {
+    if port_offset == 0 {
+        return None;
+    }
+    let (host, port) = endpoint.rsplit_once(':')?;
+    let port = port.parse::<u16>().ok()?.checked_add(port_offset)?;
+    Some(format!("{}:{}", host, port))
+}
+
+/// Where to send TCP echoes for a peer's endpoint, or `None` if it must be probed over HTTP: its
+/// capabilities are not known, or it advertises no TCP echo.
+pub fn peer_address(endpoint: &str, capabilities: Option<&Capabilities>) -> Option<String> {
+    capabilities.and_then(|capabilities| address(endpoint, capabilities.tcp_echo_port_offset))
+}
+
+/// Serves echo rounds until the client closes the connection or stays idle for `idle_timeout`.
+async fn handle(mut stream: sidevm::net::TcpStream, idle_timeout: Duration) -> Result<()> {
+    loop {
+        let frame = match with_timeout(idle_timeout, read_frame(&mut stream)).await {
+            Ok(Ok(frame)) => frame,
+            // the client closed the connection
+            Ok(Err(_)) => return Ok(()),
+            Err(_) => return Err(anyhow!("Echo connection idle for {:?}", idle_timeout)),
+        };
+        let received_at = now_us();
+        let request = EchoRequest::decode(&mut &frame[..])
+            .map_err(|err| anyhow!("Malformed echo request: {:?}", err))?;
+        let reply = EchoReply {
+            sent_at: request.sent_at,
+            received_at,
+            replied_at: now_us(),
+        };
+        write_frame(&mut stream, &reply.encode()).await?;
+    }
+}
+
+// Releases a connection slot when the connection ends.
+struct ConnectionSlot(Arc<AtomicU64>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Accepts echo connections on `address` forever, at most `tcp_echo_max_connections` at once. Failing
+/// to bind only disables the TCP echo, peers fall back to the HTTP one.
+pub async fn serve(address: Option<String>, parameters: &ProbeParameters) {
+    let listener = match &address {
+        Some(address) => sidevm::net::TcpListener::bind(address).await,
+        None => return futures::future::pending().await,
+    };
+    let listener = match listener {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Failed to bind the echo listener: {:?}", err);
+            return futures::future::pending().await;
+        }
+    };
+    info!("Echo listening on {:?}", &address);
+
+    let idle_timeout = Duration::from_millis(parameters.probe_timeout_ms);
+    let connections = Arc::new(AtomicU64::new(0));
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                if connections.fetch_add(1, Ordering::SeqCst) >= parameters.tcp_echo_max_connections {
+                    connections.fetch_sub(1, Ordering::SeqCst);
+                    warn!("Too many echo connections, closing a new one");
+                    continue;
+                }
+                let slot = ConnectionSlot(Arc::clone(&connections));
+                sidevm::spawn(async move {
+                    handle(stream, idle_timeout)
+                        .await
+                        .map_err(|err| warn!("Echo connection failed: {:?}", err))
+                        .ok();
+                    drop(slot);
+                });
+            }
+            Err(err) => warn!("Failed to accept echo connection: {:?}", err),
+        }
+    }
+}
+
+/// Sends `probe_samples` echoes over a single connection to `address`. Each round trip is bounded by
+/// `probe_timeout_ms`; the samples gathered before a failure are kept.
+pub async fn probe(address: &str, parameters: &ProbeParameters) -> Result<Vec<EchoSample>> {
+    let timeout = Duration::from_millis(parameters.probe_timeout_ms);
+    let mut stream = with_timeout(timeout, sidevm::net::TcpStream::connect(address)).await??;
+
+    let mut samples = Vec::new();
+    for _ in 0..parameters.probe_samples {
+        let request = EchoRequest { sent_at: now_us() };
+        let start = Instant::now();
+        let round_trip = async {
+            write_frame(&mut stream, &request.encode()).await?;
+            let reply = EchoReply::decode(&mut &read_frame(&mut stream).await?[..])
+                .map_err(|err| anyhow!("Malformed echo reply: {:?}", err))?;
+            if reply.sent_at != request.sent_at {
+                return Err(anyhow!("Echo reply does not match the request"));
+            }
+            Ok(reply)
+        };
+        match with_timeout(timeout, round_trip).await.and_then(|result| result) {
+            Ok(reply) => samples.push(EchoSample {
+                rtt_ms: start.elapsed().as_micros() as f64 / 1000.0,
+                clock: Some(ClockSample::new(reply.sent_at, reply.received_at, reply.replied_at, now_us())),
+            }),
+            Err(err) => {
+                warn!("TCP echo to {} failed: {:?}", address, err);
+                break;
+            }
+        }
+    }
+
+    Ok(samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn frames_round_trip() {
+        let request = EchoRequest { sent_at: 42 };
+        let reply = EchoReply { sent_at: 42, received_at: 43, replied_at: 44 };
+        let mut stream = Vec::new();
+        write_frame(&mut stream, &request.encode()).await.unwrap();
+        write_frame(&mut stream, &reply.encode()).await.unwrap();
+        assert_eq!(stream[..4], 8u32.to_be_bytes());
+
+        let mut reader = &stream[..];
+        let frame = read_frame(&mut reader).await.unwrap();
+        assert_eq!(EchoRequest::decode(&mut &frame[..]).unwrap().sent_at, 42);
+        let frame = read_frame(&mut reader).await.unwrap();
+        let decoded = EchoReply::decode(&mut &frame[..]).unwrap();
+        assert_eq!((decoded.sent_at, decoded.received_at, decoded.replied_at), (42, 43, 44));
+        // the connection was closed
+        assert!(read_frame(&mut reader).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_and_truncated_frames() {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, &[0u8; MAX_FRAME_LEN as usize]).await.unwrap();
+        assert_eq!(read_frame(&mut &stream[..]).await.unwrap().len(), MAX_FRAME_LEN as usize);
+
+        // the length alone is enough to refuse the frame
+        let oversized = (MAX_FRAME_LEN + 1).to_be_bytes();
+        let err = read_frame(&mut &oversized[..]).await.unwrap_err();
+        assert!(err.to_string().contains("too large"), "{}", err);
+
+        let mut truncated = 8u32.to_be_bytes().to_vec();
+        truncated.extend_from_slice(&[1, 2, 3]);
+        assert!(read_frame(&mut &truncated[..]).await.is_err());
+    }
+
+    #[test]
+    fn derives_the_echo_address() {
+        assert_eq!(address("10.0.0.1:8000", 1000), Some("10.0.0.1:9000".to_string()));
+        assert_eq!(address("[::1]:8000", 1), Some("[::1]:8001".to_string()));
+        assert_eq!(address("10.0.0.1:8000", 0), None);
+        assert_eq!(address("example.com", 1000), None);
+        assert_eq!(address("10.0.0.1:65000", 1000), None);
+    }
+
+    #[test]
+    fn falls_back_to_http_without_a_tcp_echo() {
+        let endpoint = "10.0.0.1:8000";
+        assert_eq!(peer_address(endpoint, None), None);
+        // older peers answer `/capabilities` with a 404, read as the defaults
+        assert_eq!(peer_address(endpoint, Some(&Capabilities::default())), None);
+        let capabilities = Capabilities { tcp_echo_port_offset: 1000 };
+        assert_eq!(peer_address(endpoint, Some(&capabilities)), Some("10.0.0.1:9000".to_string()));
+    }
+}
@@ -0,0 +1,105 @@
+use hyper::{Body, Response, StatusCode};
+use scale::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::probe::Probe;
+
+/// Errors reported to API clients, over HTTP as well as through pink queries.
+#[derive(Debug, Clone)]
+pub enum Error {
+    // the probe state is not there yet
+    NotInitialized,
+    UnknownPeer(String),
+    PeerOffline(String),
+    // the peer is known but the requested data has not been measured or resolved yet
+    NotAvailable(String),
+    BadRequest(String),
+    Forbidden(String),
+    // too many requests of this kind are outstanding, try again later
+    Busy(String),
+    Internal(String),
+}
+
+/// Body of an error reply, JSON over HTTP and SCALE in query responses.
+#[derive(Serialize, Deserialize, Encode, Decode, Debug, Clone, Default)]
+#[cfg_attr(feature = "scale-info", derive(scale_info::TypeInfo))]
+pub struct ErrorReply {
+    pub error: String,
+    pub message: String,
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::NotInitialized => "not_initialized",
+            Error::UnknownPeer(_) => "unknown_peer",
+            Error::PeerOffline(_) => "peer_offline",
+            Error::NotAvailable(_) => "not_available",
+            Error::BadRequest(_) => "bad_request",
+            Error::Forbidden(_) => "forbidden",
+            Error::Busy(_) => "busy",
+            Error::Internal(_) => "internal",
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Error::NotInitialized => StatusCode::SERVICE_UNAVAILABLE,
+            Error::UnknownPeer(_) | Error::NotAvailable(_) => StatusCode::NOT_FOUND,
+            Error::PeerOffline(_) => StatusCode::CONFLICT,
+            Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::Busy(_) => StatusCode::TOO_MANY_REQUESTS,
+            Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn reply(&self) -> ErrorReply {
+        ErrorReply {
+            error: self.code().to_string(),
+            message: self.to_string(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.reply()).expect("error replies should serialize")
+    }
+
+    pub fn into_response(self) -> Response<Body> {
+        Response::builder()
+            .status(self.status_code())
+            .header("content-type", "application/json")
+            .body(Body::from(self.to_json()))
+            .expect("error responses should build")
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotInitialized => write!(f, "probe is not initialized"),
+            Error::UnknownPeer(peer) => write!(f, "peer {} is not in the list", peer),
+            Error::PeerOffline(peer) => write!(f, "peer {} is offline", peer),
+            Error::NotAvailable(message)
+            | Error::BadRequest(message)
+            | Error::Forbidden(message)
+            | Error::Busy(message)
+            | Error::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub fn initialized(probe: &Option<Probe>) -> Result<&Probe, Error> {
+    probe.as_ref().ok_or(Error::NotInitialized)
+}
+
+pub fn initialized_mut(probe: &mut Option<Probe>) -> Result<&mut Probe, Error> {
+    probe.as_mut().ok_or(Error::NotInitialized)
+}
+
+pub fn to_json<T: Serialize>(value: &T) -> Result<String, Error> {
+    serde_json::to_string(value).map_err(|err| Error::Internal(err.to_string()))
+}
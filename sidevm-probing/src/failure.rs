@@ -0,0 +1,178 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+use crate::types::ProbeParameters;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum PeerState {
+    Online,
+    // missed probes, but still used until the detector is confident it is gone
+    Suspect,
+    Offline,
+}
+
+impl Default for PeerState {
+    fn default() -> Self {
+        PeerState::Online
+    }
+}
+
+/// Phi-accrual failure detector: successful probes are heartbeats, and the suspicion level `phi` is
+/// how unlikely it is, given the past intervals between heartbeats, to still be waiting for one.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct FailureDetector {
+    // recent intervals between heartbeats, in ms
+    intervals: VecDeque<f64>,
+    last_heartbeat: Option<u64>,
+    // first failed probe of a peer that never answered, which stands in for its last heartbeat
+    #[serde(default)]
+    first_failure: Option<u64>,
+    // when the peer was declared offline, cleared by the next heartbeat
+    #[serde(default)]
+    offline_since: Option<u64>,
+    pub phi: f64,
+    pub state: PeerState,
+}
+
+impl FailureDetector {
+    pub fn heartbeat(&mut self, now: u64, parameters: &ProbeParameters) {
+        if let Some(last_heartbeat) = self.last_heartbeat {
+            if self.intervals.len() >= parameters.phi_window as usize {
+                self.intervals.pop_front();
+            }
+            self.intervals.push_back(now.saturating_sub(last_heartbeat) as f64);
+        }
+        self.last_heartbeat = Some(now);
+        self.first_failure = None;
+        self.offline_since = None;
+        self.phi = 0.0;
+        self.state = PeerState::Online;
+    }
+
+    pub fn failure(&mut self, now: u64, parameters: &ProbeParameters) {
+        if self.last_heartbeat.is_none() {
+            self.first_failure.get_or_insert(now);
+        }
+        self.phi = self.phi_at(now, parameters);
+        self.state = if self.phi >= parameters.phi_offline {
+            PeerState::Offline
+        } else if self.phi >= parameters.phi_suspect {
+            PeerState::Suspect
+        } else {
+            PeerState::Online
+        };
+        if self.state == PeerState::Offline {
+            self.offline_since.get_or_insert(now);
+        } else {
+            self.offline_since = None;
+        }
+    }
+
+    /// How long the peer has been offline, 0 if it is not.
+    pub fn offline_for(&self, now: u64) -> u64 {
+        self.offline_since.map_or(0, |since| now.saturating_sub(since))
+    }
+
+    fn phi_at(&self, now: u64, parameters: &ProbeParameters) -> f64 {
+        let last_heartbeat = match self.last_heartbeat.or(self.first_failure) {
+            Some(last_heartbeat) => last_heartbeat,
+            None => return parameters.phi_suspect,
+        };
+        let elapsed = now.saturating_sub(last_heartbeat) as f64;
+        if self.intervals.is_empty() {
+            // without a past interval, assume the probe interval and suspect the peer from its first miss
+            let prior = phi(elapsed, parameters.probe_interval_ms as f64, parameters.phi_min_std_ms);
+            return prior.max(parameters.phi_suspect);
+        }
+
+        let n = self.intervals.len() as f64;
+        let mean = self.intervals.iter().sum::<f64>() / n;
+        let std = (self.intervals.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n)
+            .sqrt()
+            .max(parameters.phi_min_std_ms);
+        phi(elapsed, mean, std)
+    }
+}
+
+/// Suspicion after `elapsed` ms without a heartbeat, for intervals of the given mean and deviation.
+fn phi(elapsed: f64, mean: f64, std: f64) -> f64 {
+    // logistic approximation of the normal CDF
+    let y = (elapsed - mean) / std;
+    let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+    if elapsed > mean {
+        -(e / (1.0 + e)).log10()
+    } else {
+        -(1.0 - 1.0 / (1.0 + e)).log10()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parameters() -> ProbeParameters {
+        ProbeParameters {
+            phi_suspect: 3.0,
+            phi_offline: 8.0,
+            phi_window: 32,
+            phi_min_std_ms: 1000.0,
+            probe_interval_ms: 30 * 1000,
+            ..Default::default()
+        }
+    }
+
+    // a peer that answered every 30s until `last`
+    fn regular(last: u64, parameters: &ProbeParameters) -> FailureDetector {
+        let mut detector = FailureDetector::default();
+        for now in (0..=last).step_by(30 * 1000) {
+            detector.heartbeat(now, parameters);
+        }
+        detector
+    }
+
+    #[test]
+    fn suspects_then_declares_offline() {
+        let parameters = parameters();
+        let last = 300 * 1000;
+        let mut detector = regular(last, &parameters);
+
+        detector.failure(last + 30500, &parameters);
+        assert_eq!(detector.state, PeerState::Online);
+        detector.failure(last + 34000, &parameters);
+        assert_eq!(detector.state, PeerState::Suspect);
+        detector.failure(last + 40000, &parameters);
+        assert_eq!(detector.state, PeerState::Offline);
+        assert_eq!(detector.offline_for(last + 50000), 10000);
+
+        detector.heartbeat(last + 60000, &parameters);
+        assert_eq!(detector.state, PeerState::Online);
+        assert_eq!(detector.phi, 0.0);
+        assert_eq!(detector.offline_for(last + 70000), 0);
+    }
+
+    #[test]
+    fn never_answered_starts_suspect() {
+        let parameters = parameters();
+        let mut detector = FailureDetector::default();
+        detector.failure(1000, &parameters);
+        assert_eq!(detector.state, PeerState::Suspect);
+        assert_eq!(detector.phi, parameters.phi_suspect);
+
+        // measured from the first failure against the probe interval
+        detector.failure(1000 + 40000, &parameters);
+        assert_eq!(detector.state, PeerState::Offline);
+        assert!(detector.phi.is_finite());
+        assert_eq!(detector.offline_for(1000 + 50000), 10000);
+    }
+
+    #[test]
+    fn single_heartbeat_uses_the_prior() {
+        let parameters = parameters();
+        let mut detector = FailureDetector::default();
+        detector.heartbeat(0, &parameters);
+        detector.failure(1000, &parameters);
+        assert_eq!(detector.state, PeerState::Suspect);
+        detector.failure(40000, &parameters);
+        assert_eq!(detector.state, PeerState::Offline);
+    }
+}
@@ -0,0 +1,97 @@
+use crate::types::{History, HistorySample};
+
+impl HistorySample {
+    /// Merges two consecutive samples into one covering both.
+    fn merge(&self, next: &HistorySample) -> HistorySample {
+        // weighted by the number of successful probes behind each mean
+        let (wa, wb) = ((self.samples - self.failures) as f64, (next.samples - next.failures) as f64);
+        let rtt_ms = match (self.rtt_ms, next.rtt_ms) {
+            (Some(a), Some(b)) => Some((a * wa + b * wb) / (wa + wb)),
+            (a, b) => a.or(b),
+        };
+        HistorySample {
+            timestamp: self.timestamp,
+            rtt_ms,
+            samples: self.samples + next.samples,
+            failures: self.failures + next.failures,
+        }
+    }
+}
+
+impl History {
+    /// Appends a probe result, `None` being a failed probe. Once `capacity` is reached the older half
+    /// of the buffer is downsampled by merging pairs of samples, so recent data keeps full resolution.
+    pub fn record(&mut self, timestamp: u64, rtt_ms: Option<f64>, capacity: usize) {
+        self.samples.push_back(HistorySample {
+            timestamp,
+            rtt_ms,
+            samples: 1,
+            failures: rtt_ms.is_none() as u32,
+        });
+        if self.samples.len() <= capacity.max(2) {
+            return;
+        }
+
+        let older = self.samples.len() / 2;
+        let mut downsampled = self
+            .samples
+            .drain(..older)
+            .collect::<Vec<HistorySample>>()
+            .chunks(2)
+            .map(|pair| match pair {
+                [a, b] => a.merge(b),
+                [a] => a.clone(),
+                _ => unreachable!(),
+            })
+            .collect::<Vec<HistorySample>>();
+        while let Some(sample) = downsampled.pop() {
+            self.samples.push_front(sample);
+        }
+    }
+
+    pub fn since(&self, timestamp: u64) -> Vec<HistorySample> {
+        self.samples.iter().filter(|s| s.timestamp >= timestamp).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recorded(count: u64, capacity: usize) -> History {
+        let mut history = History::default();
+        for i in 0..count {
+            // every third probe fails
+            let rtt_ms = if i % 3 == 2 { None } else { Some(10.0) };
+            history.record(i * 1000, rtt_ms, capacity);
+        }
+        history
+    }
+
+    #[test]
+    fn keeps_within_capacity() {
+        let history = recorded(1000, 16);
+        assert!(history.samples.len() <= 16);
+        assert_eq!(history.samples.iter().map(|s| s.samples as u64).sum::<u64>(), 1000);
+        assert_eq!(history.samples.iter().map(|s| s.failures as u64).sum::<u64>(), 333);
+        let mut answered = history.samples.iter().filter(|s| s.failures < s.samples);
+        assert!(answered.all(|s| s.rtt_ms == Some(10.0)));
+        assert!(history.samples.iter().zip(history.samples.iter().skip(1)).all(|(a, b)| a.timestamp < b.timestamp));
+    }
+
+    #[test]
+    fn recent_samples_keep_full_resolution() {
+        let history = recorded(1000, 16);
+        let last = history.samples.back().unwrap();
+        assert_eq!(last.timestamp, 999 * 1000);
+        assert_eq!(last.samples, 1);
+    }
+
+    #[test]
+    fn since_filters_older_samples() {
+        let history = recorded(10, 16);
+        let since = history.since(7000);
+        assert_eq!(since.iter().map(|s| s.timestamp).collect::<Vec<u64>>(), vec![7000, 8000, 9000]);
+        assert_eq!(since[1].rtt_ms, None);
+    }
+}
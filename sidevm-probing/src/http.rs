@@ -0,0 +1,325 @@
+use hyper::body::Buf;
+use hyper::service::Service;
+use hyper::{Client, Method, Request, StatusCode, Uri};
+use log::{info, warn};
+use once_cell::sync::OnceCell;
+use sidevm::net::HttpConnector;
+use std::fmt;
+use std::future::Future;
+use std::io::Read;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::types::ProbeParameters;
+use crate::utils::with_timeout;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// Shared by every outgoing request so that connections to a peer are kept alive and reused.
+static CLIENT: OnceCell<Client<TimeoutConnector<HttpConnector>, String>> = OnceCell::new();
+
+// The connect timeout is a property of the shared client, so it is taken from the first request.
+fn client(connect_timeout: Duration) -> &'static Client<TimeoutConnector<HttpConnector>, String> {
+    CLIENT.get_or_init(|| {
+        Client::builder()
+            .executor(sidevm::exec::HyperExecutor)
+            .pool_max_idle_per_host(4)
+            // retries are left to `request`, so that a single-attempt latency probe is never silently resent
+            .retry_canceled_requests(false)
+            .build::<_, String>(TimeoutConnector::new(HttpConnector::new(), connect_timeout))
+    })
+}
+
+/// Returned by `TimeoutConnector` when the connection is not established in time.
+#[derive(Debug)]
+struct ConnectTimedOut;
+
+impl fmt::Display for ConnectTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "connect timed out")
+    }
+}
+
+impl std::error::Error for ConnectTimedOut {}
+
+/// Connector giving up on establishing a connection after `timeout`, which the sidevm connector has no
+/// setting for.
+#[derive(Clone)]
+pub struct TimeoutConnector<C> {
+    inner: C,
+    timeout: Duration,
+}
+
+impl<C> TimeoutConnector<C> {
+    pub fn new(inner: C, timeout: Duration) -> Self {
+        TimeoutConnector { inner, timeout }
+    }
+}
+
+impl<C> Service<Uri> for TimeoutConnector<C>
+where
+    C: Service<Uri>,
+    C::Response: Send + 'static,
+    C::Error: Into<BoxError>,
+    C::Future: Send + 'static,
+{
+    type Response = C::Response;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let timeout = self.timeout;
+        let connecting = self.inner.call(uri);
+        Box::pin(async move {
+            match with_timeout(timeout, connecting).await {
+                Ok(result) => result.map_err(Into::into),
+                Err(_) => Err(Box::new(ConnectTimedOut) as BoxError),
+            }
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum HttpError {
+    InvalidUrl(String),
+    Connect(String),
+    // no connection within the connect timeout
+    ConnectTimeout,
+    // no response head within the response timeout, connection setup included
+    ResponseTimeout,
+    // no complete body within the read timeout
+    ReadTimeout,
+    Body(String),
+    Status(StatusCode, String),
+}
+
+impl HttpError {
+    /// Whether sending the same request again may succeed.
+    fn is_transient(&self) -> bool {
+        match self {
+            HttpError::InvalidUrl(_) => false,
+            HttpError::Status(status, _) => status.is_server_error(),
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpError::InvalidUrl(err) => write!(f, "invalid url: {}", err),
+            HttpError::Connect(err) => write!(f, "connection failed: {}", err),
+            HttpError::ConnectTimeout => write!(f, "timed out connecting"),
+            HttpError::ResponseTimeout => write!(f, "timed out waiting for the response"),
+            HttpError::ReadTimeout => write!(f, "timed out reading the response body"),
+            HttpError::Body(err) => write!(f, "failed to read the response body: {}", err),
+            HttpError::Status(status, body) => write!(f, "returned {}: {}", status, body),
+        }
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+// hyper wraps the connector's error, so it is looked for among the causes
+fn timed_out_connecting(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut cause = Some(err);
+    while let Some(err) = cause {
+        if err.is::<ConnectTimedOut>() {
+            return true;
+        }
+        cause = err.source();
+    }
+    false
+}
+
+impl From<hyper::Error> for HttpError {
+    fn from(err: hyper::Error) -> Self {
+        if timed_out_connecting(&err) {
+            return HttpError::ConnectTimeout;
+        }
+        HttpError::Connect(err.to_string())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpOptions {
+    // only the value of the first request is used, see `client`
+    pub connect_timeout: Duration,
+    pub response_timeout: Duration,
+    pub read_timeout: Duration,
+    pub max_retries: u64,
+    // doubled after every attempt
+    pub retry_backoff: Duration,
+}
+
+impl HttpOptions {
+    /// Single attempt, for latency probes where a retry would skew the sample.
+    pub fn without_retries(mut self) -> Self {
+        self.max_retries = 0;
+        self
+    }
+}
+
+impl From<&ProbeParameters> for HttpOptions {
+    fn from(parameters: &ProbeParameters) -> Self {
+        HttpOptions {
+            connect_timeout: Duration::from_millis(parameters.http_connect_timeout_ms),
+            response_timeout: Duration::from_millis(parameters.http_response_timeout_ms),
+            read_timeout: Duration::from_millis(parameters.http_read_timeout_ms),
+            max_retries: parameters.http_max_retries,
+            retry_backoff: Duration::from_millis(parameters.http_retry_backoff_ms),
+        }
+    }
+}
+
+pub async fn get(url: &str, options: &HttpOptions) -> Result<Vec<u8>, HttpError> {
+    info!("Connecting to {}", url);
+    request(Method::GET, url, String::new(), options).await
+}
+
+pub async fn post(url: &str, body: String, options: &HttpOptions) -> Result<Vec<u8>, HttpError> {
+    info!("Posting to {}", url);
+    request(Method::POST, url, body, options).await
+}
+
+/// How long to wait before retrying after attempt number `attempt` (from 0) failed with `err`, if at all.
+fn retry_backoff(err: &HttpError, attempt: u64, options: &HttpOptions) -> Option<Duration> {
+    if !err.is_transient() || attempt >= options.max_retries {
+        return None;
+    }
+    Some(options.retry_backoff.saturating_mul(2u32.saturating_pow(attempt as u32)))
+}
+
+async fn request(method: Method, url: &str, body: String, options: &HttpOptions) -> Result<Vec<u8>, HttpError> {
+    let mut attempt: u64 = 0;
+    loop {
+        let err = match send(method.clone(), url, body.clone(), options).await {
+            Ok(response) => return Ok(response),
+            Err(err) => err,
+        };
+        match retry_backoff(&err, attempt, options) {
+            Some(backoff) => {
+                warn!("Request to {} failed ({}), retrying in {:?}", url, err, backoff);
+                sidevm::time::sleep(backoff).await;
+                attempt += 1;
+            }
+            None => return Err(err),
+        }
+    }
+}
+
+async fn send(method: Method, url: &str, body: String, options: &HttpOptions) -> Result<Vec<u8>, HttpError> {
+    let mut builder = Request::builder().method(method).uri(url);
+    if !body.is_empty() {
+        builder = builder.header("content-type", "application/json");
+    }
+    let request = builder
+        .body(body)
+        .map_err(|err| HttpError::InvalidUrl(err.to_string()))?;
+
+    let response = with_timeout(options.response_timeout, client(options.connect_timeout).request(request))
+        .await
+        .map_err(|_| HttpError::ResponseTimeout)??;
+    let status = response.status();
+    info!("response status: {}", status);
+
+    let mut buf = vec![];
+    with_timeout(options.read_timeout, hyper::body::aggregate(response))
+        .await
+        .map_err(|_| HttpError::ReadTimeout)?
+        .map_err(|err| HttpError::Body(err.to_string()))?
+        .reader()
+        .read_to_end(&mut buf)
+        .map_err(|err| HttpError::Body(err.to_string()))?;
+
+    if !status.is_success() {
+        return Err(HttpError::Status(status, String::from_utf8_lossy(&buf).to_string()));
+    }
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> HttpOptions {
+        HttpOptions {
+            connect_timeout: Duration::from_millis(100),
+            response_timeout: Duration::from_millis(200),
+            read_timeout: Duration::from_millis(300),
+            max_retries: 3,
+            retry_backoff: Duration::from_millis(10),
+        }
+    }
+
+    #[test]
+    fn doubles_the_backoff_until_retries_run_out() {
+        let err = HttpError::ConnectTimeout;
+        let backoffs = (0..4).map(|attempt| retry_backoff(&err, attempt, &options())).collect::<Vec<_>>();
+        assert_eq!(
+            backoffs,
+            [Some(10), Some(20), Some(40), None].map(|ms| ms.map(Duration::from_millis))
+        );
+        assert_eq!(retry_backoff(&err, 0, &options().without_retries()), None);
+    }
+
+    #[test]
+    fn only_retries_transient_errors() {
+        let transient = [
+            HttpError::Connect("refused".to_string()),
+            HttpError::ConnectTimeout,
+            HttpError::ResponseTimeout,
+            HttpError::ReadTimeout,
+            HttpError::Body("reset".to_string()),
+            HttpError::Status(StatusCode::BAD_GATEWAY, String::new()),
+        ];
+        for err in transient {
+            assert!(retry_backoff(&err, 0, &options()).is_some(), "{}", err);
+        }
+        let permanent = [
+            HttpError::InvalidUrl("bad".to_string()),
+            HttpError::Status(StatusCode::NOT_FOUND, String::new()),
+            HttpError::Status(StatusCode::TOO_MANY_REQUESTS, String::new()),
+        ];
+        for err in permanent {
+            assert_eq!(retry_backoff(&err, 0, &options()), None, "{}", err);
+        }
+    }
+
+    #[derive(Debug)]
+    struct Wrapped(ConnectTimedOut);
+
+    impl fmt::Display for Wrapped {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "client error (Connect)")
+        }
+    }
+
+    impl std::error::Error for Wrapped {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    #[test]
+    fn finds_connect_timeouts_among_causes() {
+        assert!(timed_out_connecting(&ConnectTimedOut));
+        assert!(timed_out_connecting(&Wrapped(ConnectTimedOut)));
+        assert!(!timed_out_connecting(&HttpError::Connect("refused".to_string())));
+    }
+
+    #[test]
+    fn describes_errors() {
+        assert_eq!(HttpError::ConnectTimeout.to_string(), "timed out connecting");
+        assert_eq!(
+            HttpError::Status(StatusCode::NOT_FOUND, "no route".to_string()).to_string(),
+            "returned 404 Not Found: no route"
+        );
+    }
+}
@@ -1,23 +1,45 @@
 use anyhow::{anyhow, Result};
-use log::{error, info};
+use log::{error, info, warn};
+use std::collections::HashMap;
 
+use command::{dispatch, Command};
+use directory::{Directory, PeerDirectory};
 use probe::Probe;
+use schnorrkel::Keypair;
 use router::router;
 use service::RouterService;
+#[cfg(not(feature = "test-worker-id"))]
+use utils::cache_get;
 use optimize::optimize;
 use query::init_pink_query;
-use utils::get_address_by_id;
 
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
+mod aggregate;
+mod align;
+mod clock;
+mod command;
+mod directory;
+mod distance;
+mod echo;
+mod error;
+mod failure;
+mod history;
+mod http;
+mod nearest;
 mod probe;
+mod protocol;
 mod router;
+mod schedule;
 mod query;
 mod service;
+mod signing;
+mod telemetry;
 mod optimize;
 mod types;
 mod utils;
+mod vivaldi;
 
 pub type AppState = Arc<Mutex<Option<Probe>>>;
 
@@ -26,41 +48,20 @@ async fn init_pink_input(app_state: AppState) -> Result<()> {
     loop {
         if let Some(message) = sidevm::channel::input_messages().next().await {
             let message_str = String::from_utf8_lossy(&message);
-            let msg: types::HostMessage = serde_json::from_str(&message_str)?;
-            info!("Received host message: {:?}", msg);
-            match msg.command.as_str() {
-                "add_peer" => {
-                    let mut lock = app_state.lock().await;
-                    let probe = (*lock).as_mut().expect("should be able to get probe ref");
-                    probe.add_pending_peer(msg.data.clone());
-                }
-                "start_optimize" => {
-                    let mut lock = app_state.lock().await;
-                    let probe = (*lock).as_mut().expect("should be able to get probe ref");
-                    probe.start_optimize();
-                }
-                "stop_optimize" => {
-                    let mut lock = app_state.lock().await;
-                    let probe = (*lock).as_mut().expect("should be able to get probe ref");
-                    probe.stop_optimize();
-                }
-                "save_app" => {
-                    let lock = app_state.lock().await;
-                    let probe = (*lock).as_ref().expect("should be able to get probe ref");
-                    let probe_state = serde_json::to_string(&probe)?;
-                    sidevm::ocall::local_cache_set(b"sidevm_probing::probe_state", &probe_state.as_bytes())
-                        .expect("should be able to set local cache");
-                }
-                "load_app" => {
-                    let probe_state = sidevm::ocall::local_cache_get(b"sidevm_probing::probe_state")?
-                        .ok_or(anyhow!("Probe state not found in local cache"))?;
-                    let restored_probe: Probe = serde_json::from_str(&String::from_utf8_lossy(&probe_state))?;
-                    let mut lock = app_state.lock().await;
-                    *lock = Some(restored_probe);
-                }
-                _ => {
-                    info!("Unknown message: {:?}", msg);
+            let msg: types::HostMessage = match serde_json::from_str(&message_str) {
+                Ok(msg) => msg,
+                Err(err) => {
+                    warn!("Ignoring malformed host message: {:?}", err);
+                    continue;
                 }
+            };
+            info!("Received host message: {:?}", msg);
+            let result = match Command::from_message(&msg.command, &msg.data) {
+                Ok(command) => dispatch(&app_state, command).await.map(|_| ()),
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                warn!("Host message {} failed: {}", &msg.command, err);
             }
         } else {
             info!("Input message channel closed");
@@ -70,6 +71,86 @@ async fn init_pink_input(app_state: AppState) -> Result<()> {
     // Unreachable code
 }
 
+/// Reads the sr25519 key derived by the contract. The contract starts the sidevm before filling the
+/// cache, so if the key is not there yet we wait for its `init_params` message.
+#[cfg(not(feature = "test-worker-id"))]
+async fn read_keypair() -> Result<Keypair> {
+    loop {
+        if let (Some(public_key), Some(private_key)) = (
+            cache_get::<Vec<u8>>(b"sidevm_probing::param::public_key"),
+            cache_get::<Vec<u8>>(b"sidevm_probing::param::private_key"),
+        ) {
+            let keypair = signing::keypair_from_secret(&private_key)?;
+            if keypair.public.to_bytes()[..] != public_key[..] {
+                return Err(anyhow!("Cached public key does not match the cached private key"));
+            }
+            return Ok(keypair);
+        }
+
+        info!("Waiting for the contract to provide the worker key");
+        let message = sidevm::channel::input_messages()
+            .next()
+            .await
+            .ok_or(anyhow!("Input message channel closed"))?;
+        if message != b"init_params" {
+            warn!("Ignoring host message before startup: {:?}", String::from_utf8_lossy(&message));
+        }
+    }
+}
+
+/// Test mode: the first host message is a worker id, from which a deterministic key is derived.
+#[cfg(feature = "test-worker-id")]
+async fn read_keypair() -> Result<Keypair> {
+    let message = sidevm::channel::input_messages()
+        .next()
+        .await
+        .ok_or(anyhow!("Input message channel closed"))?;
+    let message_str = String::from_utf8_lossy(&message);
+    info!("Received host message: {:?}", message_str);
+    let worker_id = message_str.parse::<u8>()?;
+    warn!("Running with test identity of worker {}", worker_id);
+
+    let mini_secret_key = schnorrkel::MiniSecretKey::from_bytes(&[worker_id; 32])
+        .map_err(|err| anyhow!("Invalid test key: {:?}", err))?;
+    Ok(mini_secret_key.expand_to_keypair(schnorrkel::ExpansionMode::Ed25519))
+}
+
+/// Applies directory host messages until the directory knows where we should listen.
+async fn wait_for_own_endpoints(encoded_public_key: &str, directory: &mut Directory) -> Result<Vec<String>> {
+    loop {
+        if let Ok(endpoints) = directory.lookup(encoded_public_key) {
+            return Ok(endpoints);
+        }
+
+        info!("Waiting for the directory to provide endpoints of {}", encoded_public_key);
+        let message = sidevm::channel::input_messages()
+            .next()
+            .await
+            .ok_or(anyhow!("Input message channel closed"))?;
+        let message_str = String::from_utf8_lossy(&message);
+        let msg: types::HostMessage = match serde_json::from_str(&message_str) {
+            Ok(msg) => msg,
+            Err(err) => {
+                warn!("Ignoring malformed host message: {:?}", err);
+                continue;
+            }
+        };
+        match msg.command.as_str() {
+            "set_directory" => {
+                let entries: HashMap<String, Vec<String>> = serde_json::from_str(&msg.data)?;
+                *directory = Directory::from_entries(entries);
+            }
+            "update_peer_endpoints" => {
+                let entry: types::HostDirectoryEntry = serde_json::from_str(&msg.data)?;
+                directory.upsert(entry.id, entry.endpoints)?;
+            }
+            _ => {
+                warn!("Ignoring host message before startup: {:?}", msg);
+            }
+        }
+    }
+}
+
 async fn init_server(address: &str, app_state: AppState) -> Result<()> {
     let router = router(app_state);
     let service = RouterService::new(router).expect("failed to create service");
@@ -93,23 +174,34 @@ async fn main() {
     sidevm::logger::Logger::with_max_level(log::Level::Trace).init();
     sidevm::ocall::enable_ocall_trace(true).unwrap();
 
-    let mut worker_id: u16 = 0;
-    if let Some(message) = sidevm::channel::input_messages().next().await {
-        let message_str = String::from_utf8_lossy(&message);
-        info!("Received host message: {:?}", message_str);
-        worker_id = message_str.parse::<u16>().unwrap();
-    }
-
-    // TODO
-    let test_public_key: &[u8] = &[0u8, 0u8, 0u8, worker_id as u8];
-    let endpoints = get_address_by_id(&hex::encode(test_public_key.clone())).await.unwrap();
+    let keypair = match read_keypair().await {
+        Ok(keypair) => keypair,
+        Err(err) => {
+            error!("Failed to read the worker identity: {:?}", err);
+            return;
+        }
+    };
+    let public_key = keypair.public.to_bytes().to_vec();
+    signing::init(keypair).expect("signing key should only be initialized once");
+    let mut directory = Directory::load();
+    let endpoints = match wait_for_own_endpoints(&hex::encode(&public_key), &mut directory).await {
+        Ok(endpoints) => endpoints,
+        Err(err) => {
+            error!("Failed to resolve own endpoints: {:?}", err);
+            return;
+        }
+    };
     let address = endpoints[0].clone();
-    let app_state = Arc::new(Mutex::new(Some(Probe::new(test_public_key.to_vec()))));
+    let probe = Probe::new(public_key, endpoints, directory);
+    let echo_address = echo::address(&address, probe.parameters.tcp_echo_port_offset);
+    let echo_parameters = probe.parameters.clone();
+    let app_state = Arc::new(Mutex::new(Some(probe)));
 
     tokio::select! {
         _ = init_pink_input(Arc::clone(&app_state)) => {},
         _ = init_pink_query(Arc::clone(&app_state)) => {},
         _ = init_server(&address, Arc::clone(&app_state)) => {},
+        _ = echo::serve(echo_address, &echo_parameters) => {},
         _ = optimize(Arc::clone(&app_state)) => {},
     }
 }
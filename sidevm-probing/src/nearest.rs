@@ -0,0 +1,186 @@
+use crate::distance::DistanceModel;
+
+/// Vantage-point tree over resolved coordinates. Each node splits the points below it at the median
+/// distance from its own coordinate, so a k-nearest search can skip the half that the triangle
+/// inequality rules out.
+#[derive(Debug, Clone, Default)]
+pub struct VpTree {
+    nodes: Vec<Node>,
+    root: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    id: String,
+    coord: Vec<f64>,
+    // points of `inside` are at most `radius` away from `coord`, those of `outside` at least
+    radius: f64,
+    inside: Option<usize>,
+    outside: Option<usize>,
+}
+
+/// Keeps the `k` closest `(distance, item)` pairs, sorted by distance.
+fn insert<T>(best: &mut Vec<(f64, T)>, k: usize, distance: f64, item: T) {
+    let position = best.partition_point(|(d, _)| *d <= distance);
+    if position < k {
+        best.insert(position, (distance, item));
+        best.truncate(k);
+    }
+}
+
+/// Distance of the k-th closest point so far, beyond which nothing is worth visiting.
+fn bound<T>(best: &[(f64, T)], k: usize) -> f64 {
+    if best.len() < k {
+        f64::INFINITY
+    } else {
+        best[k - 1].0
+    }
+}
+
+impl VpTree {
+    pub fn build(points: Vec<(String, Vec<f64>)>, model: &dyn DistanceModel) -> VpTree {
+        let mut tree = VpTree {
+            nodes: Vec::with_capacity(points.len()),
+            root: None,
+        };
+        tree.root = tree.build_node(points, model);
+        tree
+    }
+
+    fn build_node(&mut self, mut points: Vec<(String, Vec<f64>)>, model: &dyn DistanceModel) -> Option<usize> {
+        let (id, coord) = points.pop()?;
+        let mut rest = points
+            .into_iter()
+            .map(|point| (model.distance(&coord, &point.1), point))
+            .collect::<Vec<(f64, (String, Vec<f64>))>>();
+        rest.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        let outside = rest.split_off(rest.len() / 2);
+        let radius = outside.first().map(|(d, _)| *d).unwrap_or(0.0);
+
+        let index = self.nodes.len();
+        self.nodes.push(Node {
+            id,
+            coord,
+            radius,
+            inside: None,
+            outside: None,
+        });
+        self.nodes[index].inside = self.build_node(rest.into_iter().map(|(_, point)| point).collect(), model);
+        self.nodes[index].outside = self.build_node(outside.into_iter().map(|(_, point)| point).collect(), model);
+        Some(index)
+    }
+
+    /// The `k` points closest to `coord` among those accepted by `filter`, closest first.
+    pub fn nearest(
+        &self,
+        coord: &[f64],
+        k: usize,
+        model: &dyn DistanceModel,
+        filter: impl Fn(&str) -> bool,
+    ) -> Vec<(String, f64)> {
+        let mut best = Vec::new();
+        if let Some(root) = self.root.filter(|_| k > 0) {
+            self.search(root, coord, k, model, &filter, &mut best);
+        }
+        best.into_iter().map(|(d, index)| (self.nodes[index].id.clone(), d)).collect()
+    }
+
+    fn search(
+        &self,
+        index: usize,
+        coord: &[f64],
+        k: usize,
+        model: &dyn DistanceModel,
+        filter: &impl Fn(&str) -> bool,
+        best: &mut Vec<(f64, usize)>,
+    ) {
+        let node = &self.nodes[index];
+        let d = model.distance(coord, &node.coord);
+        if filter(&node.id) {
+            insert(best, k, d, index);
+        }
+
+        // the side the query falls in is the most likely to hold its neighbours, search it first
+        let children = if d < node.radius {
+            [(node.inside, true), (node.outside, false)]
+        } else {
+            [(node.outside, false), (node.inside, true)]
+        };
+        for (child, inside) in children {
+            let child = match child {
+                Some(child) => child,
+                None => continue,
+            };
+            let tau = bound(best, k);
+            let reachable = if inside {
+                d - tau <= node.radius
+            } else {
+                d + tau >= node.radius
+            };
+            if reachable {
+                self.search(child, coord, k, model, filter, best);
+            }
+        }
+    }
+}
+
+/// Exhaustive version of `VpTree::nearest`, for models that are not a metric.
+pub fn linear_scan<'a>(
+    points: impl Iterator<Item = (&'a String, &'a Vec<f64>)>,
+    coord: &[f64],
+    k: usize,
+    model: &dyn DistanceModel,
+    filter: impl Fn(&str) -> bool,
+) -> Vec<(String, f64)> {
+    let mut best = Vec::new();
+    if k == 0 {
+        return Vec::new();
+    }
+    for (id, point) in points.filter(|(id, _)| filter(id)) {
+        insert(&mut best, k, model.distance(coord, point), id.clone());
+    }
+    best.into_iter().map(|(d, id)| (id, d)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distance::{Euclidean, EuclideanHeight};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    // a position and a height
+    fn coord(rng: &mut StdRng) -> Vec<f64> {
+        vec![rng.gen_range(0.0..100.0), rng.gen_range(0.0..100.0), rng.gen_range(0.0..10.0)]
+    }
+
+    fn points(rng: &mut StdRng, n: usize) -> Vec<(String, Vec<f64>)> {
+        (0..n).map(|i| (format!("p{}", i), coord(rng))).collect()
+    }
+
+    fn distances(neighbors: &[(String, f64)]) -> Vec<f64> {
+        neighbors.iter().map(|(_, d)| *d).collect()
+    }
+
+    #[test]
+    fn matches_linear_scan() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let models: [&dyn DistanceModel; 2] = [&Euclidean, &EuclideanHeight];
+        for model in models {
+            for n in [0, 1, 2, 7, 50, 200] {
+                let points = points(&mut rng, n);
+                let tree = VpTree::build(points.clone(), model);
+                for _ in 0..10 {
+                    let coord = coord(&mut rng);
+                    for k in [0, 1, 3, 10, 500] {
+                        let filter = |id: &str| id != "p3";
+                        let indexed = tree.nearest(&coord, k, model, filter);
+                        let scanned = linear_scan(points.iter().map(|(id, p)| (id, p)), &coord, k, model, filter);
+                        assert_eq!(distances(&indexed), distances(&scanned), "n = {}, k = {}", n, k);
+                        assert!(indexed.len() <= k && indexed.iter().all(|(id, _)| id != "p3"));
+                    }
+                }
+            }
+        }
+    }
+}
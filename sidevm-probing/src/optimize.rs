@@ -3,11 +3,19 @@ use log::{info, warn};
 use std::collections::HashMap;
 use std::time::Duration;
 
+use futures::future::join_all;
 use rand::{seq::IteratorRandom, thread_rng};
 
+use crate::aggregate::aggregate;
+use crate::align::{align, AlignmentMode};
+use crate::directory::Directory;
+use crate::distance::{DistanceModel, DotProduct};
+use crate::failure::PeerState;
 use crate::probe::Peer;
-use crate::utils::{euclidean_distance, gen_random_vec};
-use crate::types::{ProbeParameters, ProbeStatus};
+use crate::telemetry;
+use crate::types::{BandwidthStats, History, LinkStats, OptimizeMode, ProbeParameters, ProbeStatus};
+use crate::utils::{now_ms, with_timeout};
+use crate::vivaldi;
 use crate::AppState;
 
 
@@ -16,8 +24,9 @@ async fn compute_loss(
     peers: &HashMap<String, Peer>,
     telemetry: &HashMap<String, f64>,
     resolved: &HashMap<String, Vec<f64>>,
-    eps: f64) -> Result<f64>
+    parameters: &ProbeParameters) -> Result<f64>
 {
+    let model = parameters.distance_model();
     let my_position: Vec<f64> = resolved
         .get(encoded_public_key)
         .expect(format!("{} should be in the resolved data", encoded_public_key).as_str())
@@ -35,56 +44,376 @@ async fn compute_loss(
         let test_peer_position = resolved
             .get(test_entry)
             .expect(format!("{} should be in the resolved data", test_entry).as_str());
-        let test_prediction = euclidean_distance(&my_position, &test_peer_position);
+        let test_prediction = model.distance(&my_position, &test_peer_position);
         let test_error = (test_label - test_prediction).abs();
-        test_total_loss += test_error / (telemetry.len() as f64 - 1.0 + eps);
+        test_total_loss += test_error / (telemetry.len() as f64 - 1.0 + parameters.eps);
         sidevm::time::maybe_rest().await;
     }
 
     Ok(test_total_loss)
 }
 
+/// Picks the peers to probe this epoch among those that are due: suspected peers first, as their state
+/// is the most uncertain, then online ones, then at most `detection_size` offline ones, the most overdue
+/// first within each group. At most `max_probes_per_epoch` peers are picked.
+fn schedule_probes(peers: &HashMap<String, Peer>, parameters: &ProbeParameters, now: u64) -> Vec<String> {
+    let mut due = peers
+        .values()
+        .filter(|peer| peer.schedule.is_due(now))
+        .collect::<Vec<&Peer>>();
+    let priority = |peer: &Peer| match peer.detector.state {
+        PeerState::Suspect => 0,
+        PeerState::Online => 1,
+        PeerState::Offline => 2,
+    };
+    due.sort_by_key(|peer| (priority(peer), peer.schedule.next_probe_at));
+
+    let mut offline = 0;
+    due.into_iter()
+        .filter(|peer| {
+            if peer.detector.state != PeerState::Offline {
+                return true;
+            }
+            offline += 1;
+            offline <= parameters.detection_size
+        })
+        .take(parameters.max_probes_per_epoch as usize)
+        .map(|peer| peer.encoded_public_key.clone())
+        .collect()
+}
+
+/// Probes every peer of the batch concurrently. A peer that fails or misses the epoch probe budget
+/// gets an offline strike.
 async fn collect_telemetry(
-    telemetry: &mut HashMap<String, f64>,
+    telemetry: &mut HashMap<String, LinkStats>,
+    history: &mut HashMap<String, History>,
     peers: &mut HashMap<String, Peer>,
     batch_peers_id: &Vec<String>,
-    beta: f64,
+    directory: &Directory,
+    parameters: &ProbeParameters,
 ) -> Result<()> {
+    let mut batch = Vec::new();
     for peer_id in batch_peers_id {
-        let mut peer = peers.get_mut(peer_id)
-            .ok_or(anyhow!("{} should be in the peers data", peer_id))?;
+        let mut peer = peers.get(peer_id)
+            .ok_or(anyhow!("{} should be in the peers data", peer_id))?
+            .clone();
 
-        peer.update_endpoints().await;
+        // keep the last known endpoints if the directory no longer knows the peer
+        peer.update_endpoints(directory)
+            .map_err(|err| warn!("Failed to update endpoints of {}: {:?}", &peer.encoded_public_key, err))
+            .ok();
+        batch.push(peer);
+    }
+
+    let budget = Duration::from_millis(parameters.epoch_probe_budget_ms);
+    let results = join_all(batch.into_iter().map(|mut peer| async move {
+        let ttl = with_timeout(budget, peer.echo(parameters)).await.and_then(|ttl| ttl);
+        (peer, ttl)
+    }))
+    .await;
+
+    let capacity = parameters.history_capacity as usize;
+    for (mut peer, ttl) in results {
+        history
+            .entry(peer.encoded_public_key.clone())
+            .or_default()
+            .record(now_ms(), ttl.as_ref().ok().cloned(), capacity);
         // collect ttl
-        match peer.echo().await {
+        match ttl {
             Ok(ttl) => {
-                peer.offline_cnt = 0;
-                if let Some(value) = telemetry.get_mut(&peer.encoded_public_key) {
-                    *value = *value * beta + ttl * (1.0 - beta);
-                } else {
-                    telemetry.insert(peer.encoded_public_key.clone(), ttl);
-                }
+                // measurements are directional: this is the outgoing latency from us to the peer,
+                // the incoming one is reported by the peer itself (see `Probe::inbound_telemetry`)
+                peer.detector.heartbeat(now_ms(), parameters);
+                peer.schedule.success(now_ms(), parameters);
+                telemetry
+                    .entry(peer.encoded_public_key.clone())
+                    .or_default()
+                    .record(ttl, now_ms(), parameters);
             },
-            Err(_) => {
-                peer.offline_cnt += 1;
+            Err(err) => {
+                warn!("Probe to {} failed: {:?}", &peer.encoded_public_key, err);
+                peer.detector.failure(now_ms(), parameters);
+                peer.schedule.failure(now_ms(), parameters);
+                telemetry
+                    .entry(peer.encoded_public_key.clone())
+                    .or_default()
+                    .record_failure(now_ms(), parameters);
             },
         };
+        peers.insert(peer.encoded_public_key.clone(), peer);
+    }
+
+    Ok(())
+}
+
+/// Measures the throughput from a few random online peers. The request latency is taken out of the
+/// transfer time using the smallest RTT seen on the link.
+async fn collect_bandwidth(
+    bandwidth: &mut HashMap<String, BandwidthStats>,
+    telemetry: &HashMap<String, LinkStats>,
+    retained_peers: &HashMap<String, Peer>,
+    parameters: &ProbeParameters,
+) {
+    let mut rng = thread_rng();
+    let batch_peers_id = retained_peers
+        .keys()
+        .cloned()
+        .choose_multiple(&mut rng, parameters.bandwidth_probe_peers as usize);
+    // one at a time, so that the probes do not compete for our own link
+    for peer_id in &batch_peers_id {
+        let peer = retained_peers.get(peer_id).expect("peer should be in the peers");
+        let (bytes, elapsed_ms) = match peer.download(parameters).await {
+            Ok(measurement) => measurement,
+            Err(err) => {
+                warn!("Bandwidth probe to {} failed: {:?}", peer_id, err);
+                continue;
+            }
+        };
+        let min_rtt_ms = telemetry.get(peer_id).map_or(0.0, |stats| stats.min_rtt_ms);
+        // never credit more than half of the time to the request latency
+        let transfer_ms = (elapsed_ms - min_rtt_ms).max(elapsed_ms / 2.0).max(parameters.eps);
+        let mbps = bytes as f64 * 8.0 / 1000.0 / transfer_ms;
+        bandwidth.entry(peer_id.clone()).or_default().record(mbps, now_ms(), parameters);
         sidevm::time::maybe_rest().await;
     }
+}
+
+async fn gradient_descent(
+    encoded_public_key: &String,
+    parameters: &ProbeParameters,
+    peers: &HashMap<String, Peer>,
+    retained_peers: &HashMap<String, Peer>,
+    telemetry: &HashMap<String, f64>,
+    resolved: &mut HashMap<String, Vec<f64>>,
+    resolved_at: &mut HashMap<String, u64>,
+) -> Result<()> {
+    let model = parameters.distance_model();
+    let mut my_position: Vec<f64> = resolved
+        .get(encoded_public_key)
+        .expect(format!("{} should be in the resolved data", encoded_public_key).as_str())
+        .to_vec();
+    let mut momentum: Vec<f64> = vec![0.0 as f64; parameters.vector_len()];
+    let mut min_loss: f64 = f64::MAX;
+    let mut current_lr: f64 = parameters.lr;
+
+    let mut iteration: u64 = 0;
+    let mut patience: u64 = 0;
+
+    loop {
+        // if it reaches the maximum number of iterations, stop optimizing
+        if iteration >= parameters.max_iters {
+            break;
+        }
+        // early return if learning rate reaches threshold
+        if &current_lr < &parameters.min_lr {
+            break;
+        }
+        iteration += 1;
+        // step 1: random sample a batch of telemetry data to process
+        let mut rng = thread_rng();
+        // here we will not choose peers that are offline
+        let batch_peers_id = retained_peers
+            .keys()
+            .cloned()
+            .choose_multiple(&mut rng, parameters.batch_size as usize);
+        // step 2: local optimize
+        let mut force: Vec<f64> = vec![0.0 as f64; parameters.vector_len()];
+        let mut peers_len: usize = 0;
+        for peer_id in &batch_peers_id {
+            let peer = peers.get(peer_id).expect("peer should be in the peers");
+            if !telemetry.contains_key(&peer.encoded_public_key) {
+                continue;
+            }
+            peers_len += 1;
+
+            let ground_truth = telemetry.get(&peer.encoded_public_key).expect(
+                format!(
+                    "{} should be in the telemetry data",
+                    &peer.encoded_public_key
+                )
+                    .as_str(),
+            );
+
+            if !resolved.contains_key(&peer.encoded_public_key) {
+                resolved.insert(
+                    peer.encoded_public_key.clone(),
+                    parameters.random_coordinate(),
+                );
+                resolved_at.insert(peer.encoded_public_key.clone(), now_ms());
+            }
+            let peer_position = resolved.get(&peer.encoded_public_key).expect(
+                format!(
+                    "{} should be in the resolved data",
+                    &peer.encoded_public_key
+                )
+                    .as_str(),
+            );
+
+            let prediction = model.distance(&my_position, &peer_position);
+            let error = ground_truth - prediction;
+            // move along the gradient of the distance, scaled by the error
+            let gradient = model.gradient(&my_position, &peer_position);
+            force = force
+                .iter()
+                .zip(gradient.iter())
+                .map(|(f, x)| f + x * error)
+                .collect::<Vec<f64>>();
+            sidevm::time::maybe_rest().await;
+        }
+        if peers_len == 0 {
+            break;
+        }
+        // step 3: update position
+        // update momentum
+        momentum = momentum
+            .iter()
+            .zip(force.iter())
+            .map(|(i, j)| {
+                i * parameters.beta + j * (1.0 - parameters.beta) / peers_len as f64
+            })
+            .collect::<Vec<f64>>();
+        // update my position
+        let step = current_lr * model.step_scale(&my_position);
+        my_position = my_position
+            .iter()
+            .zip(momentum.iter())
+            .map(|(i, j)| i + j * step)
+            .collect::<Vec<f64>>();
+        model.project(&mut my_position);
+        // step 4: calculate loss and update parameters
+        let test_total_loss = compute_loss(encoded_public_key, retained_peers, telemetry, resolved, parameters).await?;
+        if test_total_loss < min_loss {
+            min_loss = test_total_loss;
+            patience = 0;
+        } else {
+            patience += 1;
+        }
+        if patience > parameters.patience {
+            current_lr *= parameters.factor;
+            patience = 0;
+        }
+        if iteration % 1000 == 0 {
+            info!(
+                "Iteration: {}, Loss: {}, Min Loss {}, Learning Rate: {}",
+                iteration, test_total_loss, min_loss, current_lr
+            );
+        }
+    }
+
+    resolved.insert(encoded_public_key.clone(), my_position);
 
     Ok(())
 }
 
+async fn vivaldi_update(
+    encoded_public_key: &String,
+    parameters: &ProbeParameters,
+    retained_peers: &HashMap<String, Peer>,
+    telemetry: &HashMap<String, f64>,
+    resolved: &mut HashMap<String, Vec<f64>>,
+    errors: &mut HashMap<String, f64>,
+) {
+    let mut my_position: Vec<f64> = resolved
+        .get(encoded_public_key)
+        .expect(format!("{} should be in the resolved data", encoded_public_key).as_str())
+        .to_vec();
+    let mut my_error = errors.get(encoded_public_key).cloned().unwrap_or(1.0);
+
+    let mut rng = thread_rng();
+    // here we will not choose peers that are offline
+    let batch_peers_id = retained_peers
+        .keys()
+        .cloned()
+        .choose_multiple(&mut rng, parameters.batch_size as usize);
+    for peer_id in &batch_peers_id {
+        let rtt = match telemetry.get(peer_id) {
+            Some(rtt) => *rtt,
+            None => continue,
+        };
+        let peer_position = resolved
+            .entry(peer_id.clone())
+            .or_insert_with(|| parameters.random_coordinate());
+        let peer_error = errors.get(peer_id).cloned().unwrap_or(1.0);
+        vivaldi::update(&mut my_position, &mut my_error, peer_position, peer_error, rtt, parameters);
+        sidevm::time::maybe_rest().await;
+    }
+    info!("Vivaldi error: {}", my_error);
+
+    resolved.insert(encoded_public_key.clone(), my_position);
+    errors.insert(encoded_public_key.clone(), my_error);
+}
+
+/// DMF-style update: our outgoing vector is fitted to what we measured towards each peer, and our
+/// incoming vector to what each peer measured towards us.
+async fn matrix_factorization(
+    encoded_public_key: &String,
+    parameters: &ProbeParameters,
+    retained_peers: &HashMap<String, Peer>,
+    telemetry: &HashMap<String, f64>,
+    inbound_telemetry: &HashMap<String, f64>,
+    resolved: &mut HashMap<String, Vec<f64>>,
+) {
+    let model = DotProduct;
+    let mut me: Vec<f64> = resolved
+        .get(encoded_public_key)
+        .expect(format!("{} should be in the resolved data", encoded_public_key).as_str())
+        .to_vec();
+    let dim = parameters.dim_size as usize;
+
+    let peers_id = retained_peers.keys().cloned().collect::<Vec<String>>();
+    if peers_id.is_empty() {
+        return;
+    }
+    let mut rng = thread_rng();
+    for iteration in 0..parameters.max_iters {
+        let peer_id = peers_id.iter().choose(&mut rng).expect("peers should not be empty");
+        let peer = resolved
+            .entry(peer_id.clone())
+            .or_insert_with(|| parameters.random_coordinate());
+
+        // outgoing: x(me -> peer) ~ out(me) . in(peer)
+        if let Some(rtt) = telemetry.get(peer_id) {
+            let error = rtt - model.distance(&me, peer);
+            let (_, peer_incoming) = model.split(peer);
+            for i in 0..dim {
+                me[i] += parameters.mf_lr * (error * peer_incoming[i] - parameters.mf_lambda * me[i]);
+            }
+        }
+        // incoming: x(peer -> me) ~ out(peer) . in(me)
+        if let Some(rtt) = inbound_telemetry.get(peer_id) {
+            let error = rtt - model.distance(peer, &me);
+            let (peer_outgoing, _) = model.split(peer);
+            for i in 0..dim {
+                me[dim + i] += parameters.mf_lr * (error * peer_outgoing[i] - parameters.mf_lambda * me[dim + i]);
+            }
+        }
+        model.project(&mut me);
+
+        if iteration % 100 == 0 {
+            sidevm::time::maybe_rest().await;
+        }
+    }
+
+    resolved.insert(encoded_public_key.clone(), me);
+}
+
 pub async fn optimize(app_state: AppState) -> Result<()> {
     loop {
         let mut encoded_public_key: String = String::default();
         let mut parameters: ProbeParameters = ProbeParameters::default();
-        let mut telemetry: HashMap<String, f64> = HashMap::new();
+        let mut telemetry: HashMap<String, LinkStats> = HashMap::new();
+        let mut bandwidth: HashMap<String, BandwidthStats> = HashMap::new();
+        let mut history: HashMap<String, History> = HashMap::new();
+        let mut inbound_telemetry: HashMap<String, f64> = HashMap::new();
         let mut resolved: HashMap<String, Vec<f64>> = HashMap::new();
+        let mut resolved_at: HashMap<String, u64> = HashMap::new();
+        let mut errors: HashMap<String, f64> = HashMap::new();
         let mut status: ProbeStatus = ProbeStatus::default();
 
         let mut peers: HashMap<String, Peer> = HashMap::new();
         let mut pending_peer_ids: Vec<String> = Vec::new();
+        let mut directory: Directory = Directory::default();
+        let mut endpoints: Vec<String> = Vec::new();
 
         // clone a copy of necessary data
         {
@@ -93,8 +422,15 @@ pub async fn optimize(app_state: AppState) -> Result<()> {
             encoded_public_key = probe.encoded_public_key.clone();
             parameters = probe.parameters.clone();
             telemetry = probe.telemetry.clone();
+            bandwidth = probe.bandwidth.clone();
+            history = probe.history.clone();
+            inbound_telemetry = probe.inbound_telemetry.clone();
             resolved = probe.resolved.clone();
+            resolved_at = probe.resolved_at.clone();
+            errors = probe.errors.clone();
             peers = probe.peers.clone();
+            directory = probe.directory.clone();
+            endpoints = probe.endpoints.clone();
             status = probe.status.clone();
         }
 
@@ -104,205 +440,118 @@ pub async fn optimize(app_state: AppState) -> Result<()> {
         }
         sidevm::time::maybe_rest().await;
 
-        // collect telemetry
-        // here we will independently collect telemetry from 5 online peers and 5 offline peers
+        // collect telemetry from the peers whose probe is due
         {
-            let mut online_peers = peers.clone();
-            online_peers.retain(|_, peer| peer.is_online());
-            let mut offline_peers = peers.clone();
-            offline_peers.retain(|_, peer| !peer.is_online());
-
-            let mut rng = thread_rng();
-            let online_batch_peers_id = online_peers
-                .keys()
-                .cloned()
-                .choose_multiple(&mut rng, parameters.detection_size as usize);
-            let offline_batch_peers_id = offline_peers
-                .keys()
-                .cloned()
-                .choose_multiple(&mut rng, parameters.detection_size as usize);
-
-            collect_telemetry(&mut telemetry, &mut peers, &online_batch_peers_id, parameters.beta).await?;
-            collect_telemetry(&mut telemetry, &mut peers, &offline_batch_peers_id, parameters.beta).await?;
+            let batch_peers_id = schedule_probes(&peers, &parameters, now_ms());
+            collect_telemetry(&mut telemetry, &mut history, &mut peers, &batch_peers_id, &directory, &parameters).await?;
         }
         let mut retained_peers = peers.clone();
         retained_peers.retain(|_, peer| peer.is_online());
+        let rtts = telemetry::rtts(&telemetry);
+
+        if parameters.bandwidth_probe_interval > 0 && status.epoch % parameters.bandwidth_probe_interval == 0 {
+            collect_bandwidth(&mut bandwidth, &telemetry, &retained_peers, &parameters).await;
+        }
 
         sidevm::time::maybe_rest().await;
 
         // start optimizing
-        {
-            let mut my_position: Vec<f64> = resolved
-                .get(&encoded_public_key)
-                .expect(format!("{} should be in the resolved data", &encoded_public_key).as_str())
-                .to_vec();
-            let mut momentum: Vec<f64> = vec![0.0 as f64; parameters.dim_size as usize];
-            let mut min_loss: f64 = f64::MAX;
-            let mut current_lr: f64 = parameters.lr;
-
-            let mut iteration: u64 = 0;
-            let mut patience: u64 = 0;
-
-            loop {
-                // if it reaches the maximum number of iterations, stop optimizing
-                if iteration >= parameters.max_iters {
-                    break;
-                }
-                // early return if learning rate reaches threshold
-                if &current_lr < &parameters.min_lr {
-                    break;
-                }
-                iteration += 1;
-                // step 1: random sample a batch of telemetry data to process
-                let mut rng = thread_rng();
-                // here we will not choose peers that are offline
-                let batch_peers_id = retained_peers
-                    .keys()
-                    .cloned()
-                    .choose_multiple(&mut rng, parameters.batch_size as usize);
-                // step 2: local optimize
-                let mut force: Vec<f64> = vec![0.0 as f64; parameters.dim_size as usize];
-                let mut peers_len: usize = 0;
-                for peer_id in &batch_peers_id {
-                    let peer = peers.get(peer_id).expect("peer should be in the peers");
-                    if !telemetry.contains_key(&peer.encoded_public_key) {
-                        continue;
-                    }
-                    peers_len += 1;
-
-                    let ground_truth = telemetry.get(&peer.encoded_public_key).expect(
-                        format!(
-                            "{} should be in the telemetry data",
-                            &peer.encoded_public_key
-                        )
-                            .as_str(),
-                    );
-
-                    if !resolved.contains_key(&peer.encoded_public_key) {
-                        resolved.insert(
-                            peer.encoded_public_key.clone(),
-                            gen_random_vec::<f64>(parameters.dim_size as usize),
-                        );
-                    }
-                    let peer_position = resolved.get(&peer.encoded_public_key).expect(
-                        format!(
-                            "{} should be in the resolved data",
-                            &peer.encoded_public_key
-                        )
-                            .as_str(),
-                    );
-
-                    let prediction = euclidean_distance(&my_position, &peer_position);
-                    let error = ground_truth - prediction;
-                    let direction = my_position
-                        .iter()
-                        .zip(peer_position.iter())
-                        .map(|(i, j)| i - j)
-                        .collect::<Vec<f64>>();
-                    // normalize the direction and get force
-                    let norm = direction.iter().fold(0.0, |acc, x| acc + x.powi(2));
-                    force = force
-                        .iter()
-                        .zip(direction.iter())
-                        .map(|(f, x)| f + (x / (norm.sqrt() + parameters.eps)) * error)
-                        .collect::<Vec<f64>>();
-                    sidevm::time::maybe_rest().await;
-                }
-                if peers_len == 0 {
-                    break;
-                }
-                // step 3: update position
-                // update momentum
-                momentum = momentum
-                    .iter()
-                    .zip(force.iter())
-                    .map(|(i, j)| {
-                        i * parameters.beta + j * (1.0 - parameters.beta) / peers_len as f64
-                    })
-                    .collect::<Vec<f64>>();
-                // update my position
-                my_position = my_position
-                    .iter()
-                    .zip(momentum.iter())
-                    .map(|(i, j)| i + j * current_lr)
-                    .collect::<Vec<f64>>();
-                // step 4: calculate loss and update parameters
-                let test_total_loss = compute_loss(&encoded_public_key, &retained_peers, &telemetry, &resolved, parameters.eps).await?;
-                if test_total_loss < min_loss {
-                    min_loss = test_total_loss;
-                    patience = 0;
-                } else {
-                    patience += 1;
-                }
-                if patience > parameters.patience {
-                    current_lr *= parameters.factor;
-                    patience = 0;
-                }
-                if iteration % 1000 == 0 {
-                    info!(
-                        "Iteration: {}, Loss: {}, Min Loss {}, Learning Rate: {}",
-                        iteration, test_total_loss, min_loss, current_lr
-                    );
-                }
+        match parameters.optimize_mode {
+            OptimizeMode::GradientDescent => {
+                gradient_descent(
+                    &encoded_public_key,
+                    &parameters,
+                    &peers,
+                    &retained_peers,
+                    &rtts,
+                    &mut resolved,
+                    &mut resolved_at,
+                )
+                .await?;
+            }
+            OptimizeMode::Vivaldi => {
+                vivaldi_update(&encoded_public_key, &parameters, &retained_peers, &rtts, &mut resolved, &mut errors).await;
+            }
+            OptimizeMode::MatrixFactorization => {
+                matrix_factorization(&encoded_public_key, &parameters, &retained_peers, &rtts, &inbound_telemetry, &mut resolved).await;
             }
-
-            resolved.insert(encoded_public_key.clone(), my_position);
         }
 
+        resolved_at.insert(encoded_public_key.clone(), now_ms());
+
         sidevm::time::maybe_rest().await;
 
         // Aggregate from other peers' resolved.
         {
+            let model = parameters.distance_model();
             let mut rng = thread_rng();
             // here we will not choose peers that are offline
             let batch_peers_id = retained_peers
                 .keys()
                 .cloned()
                 .choose_multiple(&mut rng, parameters.sample_size as usize);
-            let mut aggregation_counter = HashMap::<String, u64>::new();
+            // every view of a coordinate, starting with our own
+            let mut contributions = HashMap::<String, Vec<Vec<f64>>>::new();
+            // maps that could not be aligned onto ours
+            let mut misaligned = 0;
+            let mode = model.alignment();
             for peer_id in &batch_peers_id {
-                let peer = peers.get(peer_id).expect("peer should be in the peers");
-                let peer_resolved = match peer.resolved().await {
-                    Ok(resolved) => resolved,
-                    Err(_) => continue,
+                let peer = peers.get_mut(peer_id).expect("peer should be in the peers");
+                let peer_resolved = match peer.resolved(&parameters).await {
+                    Ok(signed) => {
+                        errors.insert(peer_id.clone(), signed.error);
+                        if let Some(rtt) = signed.telemetry.get(&encoded_public_key) {
+                            inbound_telemetry.insert(peer_id.clone(), *rtt);
+                        }
+                        signed.resolved
+                    }
+                    Err(err) => {
+                        warn!("Rejected resolved data from {}: {:?}", peer_id, err);
+                        continue;
+                    }
                 };
+                // bring the peer's map into our frame before merging it
+                let alignment = align(mode, &resolved, &peer_resolved, parameters.dim_size as usize);
+                peer.alignment_residual = alignment.as_ref().map(|alignment| alignment.residual);
+                // an unaligned map lives in another rotation of the space, only use it for discovery
+                let aligned = alignment.is_some() || mode == AlignmentMode::Unaligned;
+                if !aligned {
+                    warn!("Not enough shared peers to align the map of {}, skipping it", peer_id);
+                    misaligned += 1;
+                }
                 for (k, v) in peer_resolved {
+                    let v = match &alignment {
+                        Some(alignment) if v.len() == parameters.vector_len() => alignment.apply(&v),
+                        _ => v,
+                    };
                     // update peers
                     if !pending_peer_ids.contains(&k) {
                         pending_peer_ids.push(k.clone());
                     }
-                    // update model
-                    if let Some(value) = resolved.get_mut(&k) {
-                        *value = (*value
-                            .iter()
-                            .zip(v.iter())
-                            .map(|(i, j)| i + j)
-                            .collect::<Vec<f64>>())
-                            .to_vec();
-                        if let Some(value) = aggregation_counter.get_mut(&k) {
-                            *value += 1;
-                        } else {
-                            aggregation_counter.insert(k.clone(), 2);
-                        }
-                    } else {
-                        resolved.insert(k.clone(), v);
-                        aggregation_counter.insert(k.clone(), 1);
+                    if !aligned {
+                        continue;
                     }
-                    sidevm::time::maybe_rest().await;
+                    contributions
+                        .entry(k.clone())
+                        .or_insert_with(|| resolved.get(&k).into_iter().cloned().collect())
+                        .push(v);
                 }
                 info!("Peers discovery: {:?}", &pending_peer_ids);
+                sidevm::time::maybe_rest().await;
             }
-            for (k, v) in &aggregation_counter {
-                let value = resolved.get_mut(k).expect("should be in the resolved data");
-                *value = (value
-                    .iter()
-                    .map(|i| i / v.clone() as f64)
-                    .collect::<Vec<f64>>())
-                    .to_vec();
+            // update model
+            status.misaligned_maps = misaligned;
+            status.rejected_contributions = 0;
+            for (k, views) in &contributions {
+                if let Some((value, rejected)) = aggregate(views, parameters.vector_len(), &parameters) {
+                    resolved.insert(k.clone(), value);
+                    resolved_at.insert(k.clone(), now_ms());
+                    status.rejected_contributions += rejected;
+                }
                 sidevm::time::maybe_rest().await;
             }
             // rebase resolved data so that the center of all positions is at the origin
-            if aggregation_counter.len() > 0 {
+            if contributions.len() > 0 && model.translation_invariant() {
                 let center = resolved.values().fold(
                     vec![0.0 as f64; parameters.dim_size as usize],
                     |acc, x| {
@@ -318,8 +567,9 @@ pub async fn optimize(app_state: AppState) -> Result<()> {
                         (
                             k.clone(),
                             v.iter()
-                                .zip(center.iter())
-                                .map(|(i, j)| i - j)
+                                .enumerate()
+                                // only positions are translated, trailing heights are kept
+                                .map(|(i, x)| if i < center.len() { x - center[i] } else { *x })
                                 .collect::<Vec<f64>>(),
                         )
                     })
@@ -327,7 +577,7 @@ pub async fn optimize(app_state: AppState) -> Result<()> {
             }
         }
 
-        status.precision_ms = compute_loss(&encoded_public_key, &retained_peers, &telemetry, &resolved, parameters.eps).await?;
+        status.precision_ms = compute_loss(&encoded_public_key, &retained_peers, &rtts, &resolved, &parameters).await?;
         status.epoch = (status.epoch + 1) % u64::MAX;
 
         sidevm::time::maybe_rest().await;
@@ -338,31 +588,104 @@ pub async fn optimize(app_state: AppState) -> Result<()> {
             let mut lock = app_state.lock().await;
             let mut probe = (*lock).as_mut().expect("should be able to get mut ref");
             probe.telemetry = telemetry;
+            probe.bandwidth = bandwidth;
+            probe.history = history;
+            probe.inbound_telemetry = inbound_telemetry;
             probe.resolved = resolved;
+            probe.resolved_at = resolved_at;
+            probe.errors = errors;
             probe.peers = peers;
             probe.pending_peer_ids.extend(pending_peer_ids);
             probe.status = status;
 
             // add pending peers
             for pending_peer_id in probe.pending_peer_ids.clone() {
-                let peer = Peer::new(pending_peer_id.clone()).await?;
+                let peer = match Peer::new(pending_peer_id.clone(), &probe.directory) {
+                    Ok(peer) => peer,
+                    Err(err) => {
+                        warn!("Failed to add peer {}: {:?}", &pending_peer_id, err);
+                        continue;
+                    }
+                };
                 let added = probe.add_peer(peer.clone()).await?;
                 if added {
                     peers_to_notify.push(peer);
                 }
             }
             probe.pending_peer_ids.clear();
-            // remove offline peers where its `offline_cnt` reaches threshold.
-            probe.peers.retain(|_, peer| peer.offline_cnt < parameters.max_offline_cnt);
+            // peers that joined us during the epoch
+            for peer in std::mem::take(&mut probe.pending_joins) {
+                probe.add_peer(peer).await?;
+            }
+            // remove peers the failure detector has held offline for `offline_evict_ms`
+            let peers_len = probe.peers.len();
+            let now = now_ms();
+            probe.peers.retain(|_, peer| peer.detector.offline_for(now) < parameters.offline_evict_ms);
+            probe.status.removed_peers = (peers_len - probe.peers.len()) as u64;
+            // and whatever we still keep about them
+            let (removed_telemetry, removed_resolved) = probe.collect_garbage(now_ms());
+            probe.status.removed_telemetry = removed_telemetry;
+            probe.status.removed_resolved = removed_resolved;
+            probe.rebuild_nearest_index();
         }
 
         for peer in peers_to_notify {
-            peer.notify_connected(encoded_public_key.clone())
+            peer.join(&encoded_public_key, &endpoints, &parameters)
                 .await
-                .map_err(|err| warn!("Failed to notify {} about the connection: {:?}", &peer.encoded_public_key, err))
+                .map_err(|err| warn!("Failed to join {}: {:?}", &peer.encoded_public_key, err))
                 .ok();
         }
 
         sidevm::time::sleep(Duration::from_secs(5)).await;
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(id: &str, state: PeerState, next_probe_at: u64) -> (String, Peer) {
+        let mut peer = Peer::with_endpoints(id.to_string(), vec![id.to_string()]).unwrap();
+        peer.detector.state = state;
+        peer.schedule.next_probe_at = next_probe_at;
+        (id.to_string(), peer)
+    }
+
+    #[test]
+    fn schedules_due_peers_by_state_then_lateness() {
+        let peers = [
+            peer("online", PeerState::Online, 50),
+            peer("late", PeerState::Online, 10),
+            peer("suspect", PeerState::Suspect, 90),
+            peer("offline", PeerState::Offline, 0),
+            peer("not due", PeerState::Suspect, 101),
+        ]
+        .into_iter()
+        .collect::<HashMap<String, Peer>>();
+        let parameters = ProbeParameters {
+            detection_size: 1,
+            max_probes_per_epoch: 16,
+            ..Default::default()
+        };
+        assert_eq!(schedule_probes(&peers, &parameters, 100), ["suspect", "late", "online", "offline"]);
+    }
+
+    #[test]
+    fn caps_offline_peers_and_the_batch() {
+        let peers = [
+            peer("a", PeerState::Offline, 1),
+            peer("b", PeerState::Offline, 2),
+            peer("c", PeerState::Offline, 3),
+            peer("d", PeerState::Online, 4),
+        ]
+        .into_iter()
+        .collect::<HashMap<String, Peer>>();
+        let mut parameters = ProbeParameters {
+            detection_size: 2,
+            max_probes_per_epoch: 16,
+            ..Default::default()
+        };
+        assert_eq!(schedule_probes(&peers, &parameters, 100), ["d", "a", "b"]);
+        parameters.max_probes_per_epoch = 2;
+        assert_eq!(schedule_probes(&peers, &parameters, 100), ["d", "a"]);
+    }
+}
@@ -1,120 +1,332 @@
 use anyhow::{Result, anyhow};
-use log::info;
-use std::collections::HashMap;
-use std::time::{SystemTime, UNIX_EPOCH};
+use log::{info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
 
+use futures::future::join_all;
+use hyper::StatusCode;
 use serde::{Deserialize, Serialize};
 
-use crate::types::{ProbeParameters, ProbeStatus};
-use crate::utils::{cache_get, euclidean_distance, gen_random_vec, get_address_by_id, http_get};
+use crate::directory::{Directory, PeerDirectory};
+use crate::schedule::ProbeSchedule;
+use crate::signing;
+use crate::telemetry;
+use crate::types::{
+    Aggregator, BandwidthStats, Capabilities, ClockEstimate, ClockSample, DistanceModelKind, EchoTimestamps, Estimate,
+    EstimateBatchEntry, EstimateBatchRequest, EstimatePair, History, HistorySample, JoinChallenge, JoinRequest, LinkStats,
+    NearestRequest, Neighbor, OptimizeMode, ProbeParameters, ProbeStatus, RttFilter, SignedResolved,
+};
+use crate::clock;
+use crate::echo::{self, EchoSample};
+use crate::error::Error;
+use crate::failure::{FailureDetector, PeerState};
+use crate::http::{self, HttpError, HttpOptions};
+use crate::nearest::{self, VpTree};
+use crate::utils::{cache_get, now_ms, now_us, percentile, with_timeout};
+use crate::vivaldi;
+
+const JOIN_CHALLENGE_TTL_MS: u64 = 30_000;
+const MAX_JOIN_CHALLENGES: usize = 64;
+const MAX_PENDING_JOINS: usize = 64;
+// number of neighbours returned by `nearest` unless asked otherwise
+pub const NEAREST_DEFAULT_K: usize = 8;
+
+/// Sends `probe_samples` echoes to `endpoint` and returns the successful ones, with RTTs in ms at
+/// microsecond resolution. Uses the TCP echo when the peer supports
+/// it and falls back to sequential HTTP echoes, each a single attempt bounded by `probe_timeout_ms`
+/// so that no retry ends up in the RTT.
+async fn echo_endpoint(
+    endpoint: &str,
+    capabilities: Option<&Capabilities>,
+    parameters: &ProbeParameters,
+) -> Vec<EchoSample> {
+    if let Some(address) = echo::peer_address(endpoint, capabilities) {
+        match echo::probe(&address, parameters).await {
+            Ok(samples) if !samples.is_empty() => return samples,
+            Ok(_) => {}
+            Err(err) => warn!("TCP echo to {} failed, falling back to HTTP: {:?}", &address, err),
+        }
+    }
+
+    let timeout = Duration::from_millis(parameters.probe_timeout_ms);
+    let options = HttpOptions::from(parameters).without_retries();
+    let mut samples = Vec::new();
+    for _ in 0..parameters.probe_samples {
+        let sent_at = now_us();
+        let url = format!("http://{}/echo/{}", endpoint, sent_at);
+        let start = Instant::now();
+        match with_timeout(timeout, http::get(&url, &options)).await {
+            Ok(Ok(response)) => {
+                let rtt_ms = start.elapsed().as_micros() as f64 / 1000.0;
+                let returned_at = now_us();
+                // older peers echo the message back without timestamps
+                let clock = serde_json::from_slice::<EchoTimestamps>(&response)
+                    .ok()
+                    .filter(|reply| reply.sent_at == sent_at)
+                    .map(|reply| ClockSample::new(sent_at, reply.received_at, reply.replied_at, returned_at));
+                samples.push(EchoSample { rtt_ms, clock });
+            }
+            Ok(Err(err)) => warn!("Echo to {} failed: {:?}", endpoint, err),
+            Err(err) => {
+                // a black-holed endpoint will not answer the next samples either
+                warn!("Echo to {} failed: {:?}", endpoint, err);
+                break;
+            }
+        }
+    }
+    samples
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Peer {
     pub encoded_public_key: String,
     pub best_endpoint: String,
     pub endpoints: Vec<String>,
-    pub offline_cnt: u8,
+    #[serde(default)]
+    pub detector: FailureDetector,
+    #[serde(default)]
+    pub schedule: ProbeSchedule,
+    // residual of the last alignment of this peer's resolved map onto ours
+    #[serde(default)]
+    pub alignment_residual: Option<f64>,
+    // recent RTT samples of each endpoint, for `RttFilter::MovingPercentile`
+    #[serde(default)]
+    pub rtt_windows: HashMap<String, VecDeque<f64>>,
+    // what the peer advertises on `/capabilities`, `None` until fetched
+    #[serde(default)]
+    pub capabilities: Option<Capabilities>,
+    // recent clock samples of the best endpoint and the estimate derived from them
+    #[serde(default)]
+    pub clock_samples: VecDeque<ClockSample>,
+    #[serde(default)]
+    pub clock: Option<ClockEstimate>,
+    // signing time of the last resolved map accepted from the peer, older ones are replays
+    #[serde(default)]
+    pub resolved_timestamp: Option<u64>,
 }
 
 impl Peer {
-    pub async fn new(encoded_public_key: String) -> Result<Self> {
-        let endpoints = get_address_by_id(&encoded_public_key).await?;
+    pub fn new(encoded_public_key: String, directory: &impl PeerDirectory) -> Result<Self> {
+        let endpoints = directory.lookup(&encoded_public_key)?;
+        Peer::with_endpoints(encoded_public_key, endpoints)
+    }
+
+    pub fn with_endpoints(encoded_public_key: String, endpoints: Vec<String>) -> Result<Self> {
+        if endpoints.is_empty() {
+            return Err(anyhow!("Peer {} has no endpoints", &encoded_public_key));
+        }
         Ok(Peer {
             encoded_public_key,
             best_endpoint: endpoints[0].clone(),
             endpoints,
-            offline_cnt: 0,
+            detector: FailureDetector::default(),
+            schedule: ProbeSchedule::default(),
+            alignment_residual: None,
+            rtt_windows: HashMap::new(),
+            capabilities: None,
+            clock_samples: VecDeque::new(),
+            clock: None,
+            resolved_timestamp: None,
         })
     }
 
-    pub async fn update_endpoints(&mut self) {
-        let endpoints = get_address_by_id(&self.encoded_public_key).await.unwrap();
+    pub fn update_endpoints(&mut self, directory: &impl PeerDirectory) -> Result<()> {
+        let endpoints = directory.lookup(&self.encoded_public_key)?;
+        if !endpoints.contains(&self.best_endpoint) {
+            self.best_endpoint = endpoints[0].clone();
+        }
+        self.rtt_windows.retain(|endpoint, _| endpoints.contains(endpoint));
         self.endpoints = endpoints;
+
+        Ok(())
     }
 
-    pub async fn echo(&mut self) -> Result<f64> {
+    /// Measures the RTT to every endpoint with `probe_samples` echoes each, keeps the best endpoint
+    /// and returns its latency after applying the configured filter.
+    pub async fn echo(&mut self, parameters: &ProbeParameters) -> Result<f64> {
         info!("Echo to peer {}", &self.encoded_public_key);
-        let start = SystemTime::now();
-        let start_since_the_epoch = start
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards");
 
-        let mut best_latency = u128::MAX;
-        let mut all_endpoints_failed = true;
+        if self.capabilities.is_none() {
+            self.capabilities = self.fetch_capabilities(parameters).await
+                .map_err(|err| warn!("Failed to fetch capabilities of {}: {:?}", &self.encoded_public_key, err))
+                .ok();
+        }
 
-        for endpoint in &self.endpoints {
-            let start_ms = start_since_the_epoch.as_millis();
+        // all endpoints are probed concurrently
+        let capabilities = self.capabilities.as_ref();
+        let measurements = join_all(self.endpoints.iter().map(|endpoint| async move {
+            (endpoint.clone(), echo_endpoint(endpoint, capabilities, parameters).await)
+        }))
+        .await;
 
-            let url = format!("http://{}/echo/{}", endpoint, &start_ms);
-            match http_get(&url).await {
-                Ok(_) => {
-                    all_endpoints_failed = false;
-                }
-                Err(_) => {
-                    continue;
-                }
+        let mut best: Option<(String, f64)> = None;
+        let mut best_clock_samples = Vec::new();
+        for (endpoint, echoes) in measurements {
+            if echoes.is_empty() {
+                continue;
+            }
+            let samples = echoes.iter().map(|echo| echo.rtt_ms).collect::<Vec<f64>>();
+            if parameters.rtt_filter == RttFilter::MovingPercentile {
+                self.record_rtt_window(&endpoint, &samples, parameters);
+            }
+            // endpoints are compared on this round's samples only
+            let latency = match parameters.rtt_filter {
+                RttFilter::Min => percentile(&samples, 0.0),
+                RttFilter::Median | RttFilter::MovingPercentile => percentile(&samples, 0.5),
+            };
+            if best.as_ref().map_or(true, |(_, best_latency)| latency < *best_latency) {
+                best = Some((endpoint, latency));
+                best_clock_samples = echoes.iter().filter_map(|echo| echo.clock).collect();
             }
+        }
 
-            let end = SystemTime::now();
-            let end_since_the_epoch = end.duration_since(UNIX_EPOCH).expect("Time went backwards");
-            let end_ms = end_since_the_epoch.as_millis();
+        let (endpoint, latency) = best.ok_or(anyhow!("All endpoints failed"))?;
+        if endpoint != self.best_endpoint {
+            self.best_endpoint = endpoint;
+            self.clock_samples.clear();
+        }
 
-            if end_ms - start_ms < best_latency {
-                self.best_endpoint = endpoint.clone();
-                best_latency = end_ms - start_ms;
+        for sample in best_clock_samples {
+            if self.clock_samples.len() >= parameters.clock_window as usize {
+                self.clock_samples.pop_front();
             }
+            self.clock_samples.push_back(sample);
+        }
+        if let Some(estimate) = clock::estimate(&self.clock_samples, now_ms()) {
+            self.clock = Some(estimate);
         }
 
-        if all_endpoints_failed {
-            return Err(anyhow!("All endpoints failed"));
+        if parameters.rtt_filter != RttFilter::MovingPercentile {
+            return Ok(latency);
         }
+        Ok(self.rtt_window_percentile(&self.best_endpoint, parameters))
+    }
 
-        // TODO: remove delay
-        Ok((best_latency + 100) as f64)
+    /// Adds samples to the moving window of `endpoint`, which is kept even while another endpoint is
+    /// the best one.
+    fn record_rtt_window(&mut self, endpoint: &str, samples: &[f64], parameters: &ProbeParameters) {
+        let window = self.rtt_windows.entry(endpoint.to_string()).or_default();
+        for sample in samples {
+            if window.len() >= parameters.rtt_window as usize {
+                window.pop_front();
+            }
+            window.push_back(*sample);
+        }
     }
 
-    pub async fn resolved(&self) -> Result<HashMap<String, Vec<f64>>> {
+    fn rtt_window_percentile(&self, endpoint: &str, parameters: &ProbeParameters) -> f64 {
+        let window = self.rtt_windows.get(endpoint).cloned().unwrap_or_default();
+        percentile(&window.into_iter().collect::<Vec<f64>>(), parameters.rtt_percentile)
+    }
+
+    /// Downloads `bandwidth_probe_bytes` from the best endpoint and returns the size received and the
+    /// time it took in ms, request latency included.
+    pub async fn download(&self, parameters: &ProbeParameters) -> Result<(usize, f64)> {
+        info!("Bandwidth probe to peer {}", &self.encoded_public_key);
+        let url = format!("http://{}/payload/{}", &self.best_endpoint, parameters.bandwidth_probe_bytes);
+        let start = Instant::now();
+        let response = http::get(&url, &HttpOptions::from(parameters).without_retries()).await?;
+        Ok((response.len(), start.elapsed().as_micros() as f64 / 1000.0))
+    }
+
+    async fn fetch_capabilities(&self, parameters: &ProbeParameters) -> Result<Capabilities> {
+        let url = format!("http://{}/capabilities", &self.best_endpoint);
+        match http::get(&url, &HttpOptions::from(parameters)).await {
+            Ok(response) => Ok(serde_json::from_slice(&response)?),
+            // older peers only speak the HTTP echo
+            Err(HttpError::Status(StatusCode::NOT_FOUND, _)) => Ok(Capabilities::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Fetches and verifies the peer's signed map.
+    pub async fn resolved(&mut self, parameters: &ProbeParameters) -> Result<SignedResolved> {
         info!("Fetch resolved data from peer {}", &self.encoded_public_key);
         let url = format!("http://{}/resolved", &self.best_endpoint);
-        let response = http_get(&url).await?;
-        let text = String::from_utf8(response).expect("Resolved data should be parseable");
-        let resolved: HashMap<String, Vec<f64>> = serde_json::from_str(&text)?;
+        let response = http::get(&url, &HttpOptions::from(parameters)).await?;
+        let text = String::from_utf8(response)?;
+        let signed: SignedResolved = serde_json::from_str(&text)?;
+        self.accept_resolved(signed, now_ms(), parameters)
+    }
 
-        Ok(resolved)
+    /// Checks that a map is signed by the peer and not a replay: it must be signed no earlier than the
+    /// last accepted one, which a restart of the peer does not reset, and within `resolved_ttl_ms`.
+    /// Fetching the same map twice is fine, it simply brings nothing new.
+    fn accept_resolved(
+        &mut self,
+        signed: SignedResolved,
+        now: u64,
+        parameters: &ProbeParameters,
+    ) -> Result<SignedResolved> {
+        if signed.public_key != self.encoded_public_key {
+            return Err(anyhow!(
+                "Resolved data from {} is signed by {}",
+                &self.encoded_public_key,
+                &signed.public_key
+            ));
+        }
+        let signature = hex::decode(&signed.signature)
+            .map_err(|err| anyhow!("Malformed signature: {:?}", err))?;
+        signing::verify(&self.encoded_public_key, &signed.signing_payload(), &signature)?;
+        if let Some(last_timestamp) = self.resolved_timestamp {
+            if signed.timestamp < last_timestamp {
+                return Err(anyhow!(
+                    "Resolved data from {} is older than the last accepted one ({} < {})",
+                    &self.encoded_public_key,
+                    signed.timestamp,
+                    last_timestamp
+                ));
+            }
+        }
+        if now.saturating_sub(signed.timestamp) > parameters.resolved_ttl_ms {
+            return Err(anyhow!(
+                "Resolved data from {} is stale (signed at {})",
+                &self.encoded_public_key,
+                signed.timestamp
+            ));
+        }
+        self.resolved_timestamp = Some(signed.timestamp);
+
+        Ok(signed)
     }
 
-    pub async fn notify_connected(&self, encoded_public_key: String) -> Result<()> {
-        info!("Notify connected to peer {} from {}", &self.encoded_public_key, &encoded_public_key);
-        let url = format!("http://{}/connected/{}", &self.best_endpoint, &encoded_public_key);
-        http_get(&url).await?;
+    /// Asks the peer to add us, proving ownership of `encoded_public_key` by signing its challenge.
+    pub async fn join(
+        &self,
+        encoded_public_key: &str,
+        endpoints: &Vec<String>,
+        parameters: &ProbeParameters,
+    ) -> Result<()> {
+        info!("Join peer {} from {}", &self.encoded_public_key, encoded_public_key);
+        let options = HttpOptions::from(parameters);
+        let url = format!("http://{}/join/challenge", &self.best_endpoint);
+        let response = http::get(&url, &options).await?;
+        let challenge: JoinChallenge = serde_json::from_slice(&response)?;
+
+        let mut request = JoinRequest {
+            public_key: encoded_public_key.to_string(),
+            endpoints: endpoints.clone(),
+            fingerprint: parameters.fingerprint(),
+            nonce: challenge.nonce,
+            signature: String::new(),
+        };
+        request.signature = hex::encode(signing::sign(&request.signing_payload(&self.encoded_public_key))?);
+
+        let url = format!("http://{}/join", &self.best_endpoint);
+        http::post(&url, serde_json::to_string(&request)?, &options).await?;
 
         Ok(())
     }
 
+    /// Suspected peers are still considered online, only the failure detector declares them offline.
     pub fn is_online(&self) -> bool {
-        self.offline_cnt == 0
+        self.detector.state != PeerState::Offline
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Probe {
-    // identity
-    pub encoded_public_key: String,
-    // params
-    pub parameters: ProbeParameters,
-    // storages
-    pub telemetry: HashMap<String, f64>,
-    pub resolved: HashMap<String, Vec<f64>>,
-    pub peers: HashMap<String, Peer>,
-    pub pending_peer_ids: Vec<String>,
-    // runtime status
-    pub status: ProbeStatus,
-}
-
-impl Probe {
-    pub fn new(public_key: Vec<u8>) -> Probe {
-        let encoded_public_key = hex::encode(public_key);
-
+impl ProbeParameters {
+    /// Parameters configured in the local cache, with defaults for the missing ones.
+    pub fn load() -> ProbeParameters {
         // get parameters from cache
         let dim_size = cache_get::<u64>(b"sidevm_probing::param::dim_size").unwrap_or(3 as u64);
         let sample_size =
@@ -139,25 +351,103 @@ impl Probe {
             / 1e6 as f64;
         let max_iters =
             cache_get::<u64>(b"sidevm_probing::param::max_iters").unwrap_or(10000 as u64);
-        let max_offline_cnt =
-            cache_get::<u8>(b"sidevm_probing::param::max_offline_cnt").unwrap_or(16 as u8);
-
-        // initialize local database
-        let mut telemetry = HashMap::new();
-        let mut resolved = HashMap::new();
-
-        telemetry.insert(encoded_public_key.clone(), 0 as f64);
-        resolved.insert(
-            encoded_public_key.clone(),
-            gen_random_vec::<f64>(dim_size as usize),
-        );
+        let aggregator = cache_get::<u8>(b"sidevm_probing::param::aggregator")
+            .and_then(Aggregator::from_code)
+            .unwrap_or_default();
+        let trim_ratio = cache_get::<u64>(b"sidevm_probing::param::trim_ratio").unwrap_or(2 * 1e5 as u64)
+            as f64
+            / 1e6 as f64;
+        let krum_byzantine =
+            cache_get::<u64>(b"sidevm_probing::param::krum_byzantine").unwrap_or(1 as u64);
+        let optimize_mode = cache_get::<u8>(b"sidevm_probing::param::optimize_mode")
+            .and_then(OptimizeMode::from_code)
+            .unwrap_or_default();
+        let vivaldi_cc = cache_get::<u64>(b"sidevm_probing::param::vivaldi_cc").unwrap_or(25 * 1e4 as u64)
+            as f64
+            / 1e6 as f64;
+        let vivaldi_ce = cache_get::<u64>(b"sidevm_probing::param::vivaldi_ce").unwrap_or(25 * 1e4 as u64)
+            as f64
+            / 1e6 as f64;
+        let distance_model = cache_get::<u8>(b"sidevm_probing::param::distance_model")
+            .and_then(DistanceModelKind::from_code)
+            .unwrap_or_default();
+        let sphere_radius =
+            cache_get::<u64>(b"sidevm_probing::param::sphere_radius").unwrap_or(100 as u64) as f64;
+        let hyperbolic_scale =
+            cache_get::<u64>(b"sidevm_probing::param::hyperbolic_scale").unwrap_or(50 as u64) as f64;
+        let probe_samples =
+            cache_get::<u64>(b"sidevm_probing::param::probe_samples").unwrap_or(3 as u64).max(1);
+        let rtt_filter = cache_get::<u8>(b"sidevm_probing::param::rtt_filter")
+            .and_then(RttFilter::from_code)
+            .unwrap_or_default();
+        let rtt_percentile = cache_get::<u64>(b"sidevm_probing::param::rtt_percentile").unwrap_or(25 * 1e4 as u64)
+            as f64
+            / 1e6 as f64;
+        let rtt_window = cache_get::<u64>(b"sidevm_probing::param::rtt_window").unwrap_or(32 as u64).max(1);
+        let probe_timeout_ms =
+            cache_get::<u64>(b"sidevm_probing::param::probe_timeout_ms").unwrap_or(2000 as u64);
+        let epoch_probe_budget_ms =
+            cache_get::<u64>(b"sidevm_probing::param::epoch_probe_budget_ms").unwrap_or(10000 as u64);
+        let http_connect_timeout_ms =
+            cache_get::<u64>(b"sidevm_probing::param::http_connect_timeout_ms").unwrap_or(1000 as u64);
+        let http_response_timeout_ms =
+            cache_get::<u64>(b"sidevm_probing::param::http_response_timeout_ms").unwrap_or(3000 as u64);
+        let http_read_timeout_ms =
+            cache_get::<u64>(b"sidevm_probing::param::http_read_timeout_ms").unwrap_or(5000 as u64);
+        let http_max_retries = cache_get::<u64>(b"sidevm_probing::param::http_max_retries").unwrap_or(2 as u64);
+        let http_retry_backoff_ms =
+            cache_get::<u64>(b"sidevm_probing::param::http_retry_backoff_ms").unwrap_or(200 as u64);
+        let tcp_echo_port_offset =
+            cache_get::<u16>(b"sidevm_probing::param::tcp_echo_port_offset").unwrap_or(1000 as u16);
+        let tcp_echo_max_connections =
+            cache_get::<u64>(b"sidevm_probing::param::tcp_echo_max_connections").unwrap_or(64 as u64);
+        let clock_window = cache_get::<u64>(b"sidevm_probing::param::clock_window").unwrap_or(16 as u64).max(1);
+        let max_loss_rate = cache_get::<u64>(b"sidevm_probing::param::max_loss_rate").unwrap_or(5 * 1e5 as u64)
+            as f64
+            / 1e6 as f64;
+        let bandwidth_probe_interval =
+            cache_get::<u64>(b"sidevm_probing::param::bandwidth_probe_interval").unwrap_or(10 as u64);
+        let bandwidth_probe_peers =
+            cache_get::<u64>(b"sidevm_probing::param::bandwidth_probe_peers").unwrap_or(1 as u64);
+        let bandwidth_probe_bytes =
+            cache_get::<u64>(b"sidevm_probing::param::bandwidth_probe_bytes").unwrap_or(256 * 1024 as u64);
+        let max_payload_bytes =
+            cache_get::<u64>(b"sidevm_probing::param::max_payload_bytes").unwrap_or(1024 * 1024 as u64);
+        let max_batch_pairs =
+            cache_get::<u64>(b"sidevm_probing::param::max_batch_pairs").unwrap_or(1024 as u64);
+        let history_capacity =
+            cache_get::<u64>(b"sidevm_probing::param::history_capacity").unwrap_or(256 as u64);
+        let telemetry_ttl_ms =
+            cache_get::<u64>(b"sidevm_probing::param::telemetry_ttl_ms").unwrap_or(10 * 60 * 1000 as u64);
+        let resolved_ttl_ms =
+            cache_get::<u64>(b"sidevm_probing::param::resolved_ttl_ms").unwrap_or(30 * 60 * 1000 as u64);
+        let phi_suspect = cache_get::<u64>(b"sidevm_probing::param::phi_suspect").unwrap_or(3 * 1e6 as u64) as f64
+            / 1e6 as f64;
+        let phi_offline = cache_get::<u64>(b"sidevm_probing::param::phi_offline").unwrap_or(8 * 1e6 as u64) as f64
+            / 1e6 as f64;
+        let phi_window = cache_get::<u64>(b"sidevm_probing::param::phi_window").unwrap_or(32 as u64).max(1);
+        let phi_min_std_ms =
+            cache_get::<u64>(b"sidevm_probing::param::phi_min_std_ms").unwrap_or(1000 as u64) as f64;
+        let offline_evict_ms =
+            cache_get::<u64>(b"sidevm_probing::param::offline_evict_ms").unwrap_or(30 * 60 * 1000 as u64);
+        let probe_interval_ms =
+            cache_get::<u64>(b"sidevm_probing::param::probe_interval_ms").unwrap_or(30 * 1000 as u64);
+        let probe_jitter = cache_get::<u64>(b"sidevm_probing::param::probe_jitter").unwrap_or(2 * 1e5 as u64) as f64
+            / 1e6 as f64;
+        let probe_backoff_base_ms =
+            cache_get::<u64>(b"sidevm_probing::param::probe_backoff_base_ms").unwrap_or(10 * 1000 as u64);
+        let probe_backoff_max_ms =
+            cache_get::<u64>(b"sidevm_probing::param::probe_backoff_max_ms").unwrap_or(60 * 60 * 1000 as u64);
+        let max_probes_per_epoch =
+            cache_get::<u64>(b"sidevm_probing::param::max_probes_per_epoch").unwrap_or(16 as u64);
+        let mf_lr = cache_get::<u64>(b"sidevm_probing::param::mf_lr").unwrap_or(1 * 1e3 as u64)
+            as f64
+            / 1e6 as f64;
+        let mf_lambda = cache_get::<u64>(b"sidevm_probing::param::mf_lambda").unwrap_or(1 * 1e4 as u64)
+            as f64
+            / 1e6 as f64;
 
-        // sidevm::ocall::local_cache_set(b"sidevm_probing::telemetry", &serde_json::to_string(&telemetry).unwrap().as_bytes()).unwrap();
-        // sidevm::ocall::local_cache_set(b"sidevm_probing::resolve", &resolved.encode()).unwrap();
-        // sidevm::ocall::local_cache_set(b"sidevm_probing::momentum", &momentum.encode()).unwrap();
 
-        info!("Configuration for the probe:");
-        info!("\t public key: {:?}", encoded_public_key);
         info!("\t dim size: {:?}", dim_size);
         info!("\t sample size: {:?}", sample_size);
         info!("\t detection size: {:?}", detection_size);
@@ -168,32 +458,221 @@ impl Probe {
         info!("\t factor: {:?}", factor);
         info!("\t min lr: {:?}", min_lr);
         info!("\t max iters: {:?}", max_iters);
-        info!("\t max offline cnt: {:?}", max_offline_cnt);
+        info!("\t aggregator: {:?}", aggregator);
+        info!("\t trim ratio: {:?}", trim_ratio);
+        info!("\t krum byzantine: {:?}", krum_byzantine);
+        info!("\t optimize mode: {:?}", optimize_mode);
+        info!("\t vivaldi cc: {:?}", vivaldi_cc);
+        info!("\t vivaldi ce: {:?}", vivaldi_ce);
+        info!("\t distance model: {:?}", distance_model);
+        info!("\t sphere radius: {:?}", sphere_radius);
+        info!("\t hyperbolic scale: {:?}", hyperbolic_scale);
+        info!("\t mf lr: {:?}", mf_lr);
+        info!("\t mf lambda: {:?}", mf_lambda);
+        info!("\t probe samples: {:?}", probe_samples);
+        info!("\t rtt filter: {:?}", rtt_filter);
+        info!("\t rtt percentile: {:?}", rtt_percentile);
+        info!("\t rtt window: {:?}", rtt_window);
+        info!("\t probe timeout ms: {:?}", probe_timeout_ms);
+        info!("\t epoch probe budget ms: {:?}", epoch_probe_budget_ms);
+        info!("\t http connect timeout ms: {:?}", http_connect_timeout_ms);
+        info!("\t http response timeout ms: {:?}", http_response_timeout_ms);
+        info!("\t http read timeout ms: {:?}", http_read_timeout_ms);
+        info!("\t http max retries: {:?}", http_max_retries);
+        info!("\t http retry backoff ms: {:?}", http_retry_backoff_ms);
+        info!("\t tcp echo port offset: {:?}", tcp_echo_port_offset);
+        info!("\t tcp echo max connections: {:?}", tcp_echo_max_connections);
+        info!("\t clock window: {:?}", clock_window);
+        info!("\t max loss rate: {:?}", max_loss_rate);
+        info!("\t bandwidth probe interval: {:?}", bandwidth_probe_interval);
+        info!("\t bandwidth probe peers: {:?}", bandwidth_probe_peers);
+        info!("\t bandwidth probe bytes: {:?}", bandwidth_probe_bytes);
+        info!("\t max payload bytes: {:?}", max_payload_bytes);
+        info!("\t max batch pairs: {:?}", max_batch_pairs);
+        info!("\t history capacity: {:?}", history_capacity);
+        info!("\t telemetry ttl ms: {:?}", telemetry_ttl_ms);
+        info!("\t resolved ttl ms: {:?}", resolved_ttl_ms);
+        info!("\t phi suspect: {:?}", phi_suspect);
+        info!("\t phi offline: {:?}", phi_offline);
+        info!("\t phi window: {:?}", phi_window);
+        info!("\t phi min std ms: {:?}", phi_min_std_ms);
+        info!("\t offline evict ms: {:?}", offline_evict_ms);
+        info!("\t probe interval ms: {:?}", probe_interval_ms);
+        info!("\t probe jitter: {:?}", probe_jitter);
+        info!("\t probe backoff base ms: {:?}", probe_backoff_base_ms);
+        info!("\t probe backoff max ms: {:?}", probe_backoff_max_ms);
+        info!("\t max probes per epoch: {:?}", max_probes_per_epoch);
+
+        ProbeParameters {
+            dim_size,
+            sample_size,
+            detection_size,
+            batch_size,
+            beta,
+            lr,
+            patience,
+            factor,
+            min_lr,
+            max_iters,
+            aggregator,
+            trim_ratio,
+            krum_byzantine,
+            optimize_mode,
+            vivaldi_cc,
+            vivaldi_ce,
+            distance_model,
+            sphere_radius,
+            hyperbolic_scale,
+            mf_lr,
+            mf_lambda,
+            probe_samples,
+            rtt_filter,
+            rtt_percentile,
+            rtt_window,
+            probe_timeout_ms,
+            epoch_probe_budget_ms,
+            http_connect_timeout_ms,
+            http_response_timeout_ms,
+            http_read_timeout_ms,
+            http_max_retries,
+            http_retry_backoff_ms,
+            tcp_echo_port_offset,
+            tcp_echo_max_connections,
+            clock_window,
+            max_loss_rate,
+            bandwidth_probe_interval,
+            bandwidth_probe_peers,
+            bandwidth_probe_bytes,
+            max_payload_bytes,
+            max_batch_pairs,
+            history_capacity,
+            telemetry_ttl_ms,
+            resolved_ttl_ms,
+            phi_suspect,
+            phi_offline,
+            phi_window,
+            phi_min_std_ms,
+            offline_evict_ms,
+            probe_interval_ms,
+            probe_jitter,
+            probe_backoff_base_ms,
+            probe_backoff_max_ms,
+            max_probes_per_epoch,
+            eps: 1e-6 as f64,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Probe {
+    // identity
+    pub encoded_public_key: String,
+    // params
+    pub parameters: ProbeParameters,
+    // storages
+    // links measured by us towards each peer (outgoing direction)
+    #[serde(deserialize_with = "telemetry::deserialize_telemetry")]
+    pub telemetry: HashMap<String, LinkStats>,
+    // throughput measured by us from each peer
+    #[serde(default)]
+    pub bandwidth: HashMap<String, BandwidthStats>,
+    // raw probe results towards each peer
+    #[serde(default)]
+    pub history: HashMap<String, History>,
+    // RTT measured by each peer towards us (incoming direction), as reported in its signed `/resolved`
+    #[serde(default)]
+    pub inbound_telemetry: HashMap<String, f64>,
+    pub resolved: HashMap<String, Vec<f64>>,
+    // when each resolved entry was last computed or aggregated, in ms
+    #[serde(default)]
+    pub resolved_at: HashMap<String, u64>,
+    // local error estimates (Vivaldi), our own and as reported by peers
+    #[serde(default)]
+    pub errors: HashMap<String, f64>,
+    pub peers: HashMap<String, Peer>,
+    pub pending_peer_ids: Vec<String>,
+    // verified joiners, added to `peers` at the end of the epoch like `pending_peer_ids`
+    #[serde(default)]
+    pub pending_joins: Vec<Peer>,
+    // states saved before the directory existed start from the configured one
+    #[serde(default = "Directory::load")]
+    pub directory: Directory,
+    // our own endpoints, advertised when joining peers
+    #[serde(default)]
+    pub endpoints: Vec<String>,
+    // outstanding join challenges, by nonce, with their expiry
+    #[serde(skip)]
+    pub join_challenges: HashMap<String, u64>,
+    // index of the resolved coordinates of online nodes, rebuilt every epoch
+    #[serde(skip)]
+    pub nearest_index: Option<VpTree>,
+    // runtime status
+    pub status: ProbeStatus,
+}
+
+impl Probe {
+    pub fn new(public_key: Vec<u8>, endpoints: Vec<String>, directory: Directory) -> Probe {
+        let encoded_public_key = hex::encode(public_key);
+
+        info!("Configuration for the probe:");
+        info!("\t public key: {:?}", encoded_public_key);
+        let parameters = ProbeParameters::load();
+
+        Probe::with_parameters(encoded_public_key, endpoints, directory, parameters)
+    }
+
+    /// A fresh probe that only knows itself, at a random coordinate.
+    pub fn with_parameters(
+        encoded_public_key: String,
+        endpoints: Vec<String>,
+        directory: Directory,
+        parameters: ProbeParameters,
+    ) -> Probe {
+        // initialize local database
+        let mut telemetry = HashMap::new();
+        let mut resolved = HashMap::new();
+        let mut resolved_at = HashMap::new();
+
+        let mut errors = HashMap::new();
+
+        telemetry.insert(encoded_public_key.clone(), LinkStats::local(now_ms()));
+        resolved.insert(
+            encoded_public_key.clone(),
+            parameters.random_coordinate(),
+        );
+        resolved_at.insert(encoded_public_key.clone(), now_ms());
+        errors.insert(encoded_public_key.clone(), 1.0 as f64);
+
+        // sidevm::ocall::local_cache_set(b"sidevm_probing::telemetry", &serde_json::to_string(&telemetry).unwrap().as_bytes()).unwrap();
+        // sidevm::ocall::local_cache_set(b"sidevm_probing::resolve", &resolved.encode()).unwrap();
+        // sidevm::ocall::local_cache_set(b"sidevm_probing::momentum", &momentum.encode()).unwrap();
 
         Probe {
             encoded_public_key,
-            parameters: ProbeParameters {
-                dim_size,
-                sample_size,
-                detection_size,
-                batch_size,
-                beta,
-                lr,
-                patience,
-                factor,
-                min_lr,
-                max_iters,
-                max_offline_cnt,
-                eps: 1e-6 as f64,
-            },
+            parameters,
             telemetry,
+            bandwidth: HashMap::new(),
+            history: HashMap::new(),
+            inbound_telemetry: HashMap::new(),
             resolved,
+            resolved_at,
+            errors,
             peers: HashMap::new(),
             pending_peer_ids: Vec::new(),
+            pending_joins: Vec::new(),
+            directory,
+            endpoints,
+            join_challenges: HashMap::new(),
+            nearest_index: None,
             status: ProbeStatus {
                 is_optimizing: false,
                 precision_ms: 0.0,
                 epoch: 0,
+                rejected_contributions: 0,
+                misaligned_maps: 0,
+                removed_peers: 0,
+                removed_telemetry: 0,
+                removed_resolved: 0,
             },
         }
     }
@@ -215,42 +694,332 @@ impl Probe {
         }
     }
 
-    pub fn get_best_endpoint_to(&self, encoded_public_key_to: String) -> Result<String> {
-        if let Some(peer_to) = self.peers.get(&encoded_public_key_to) {
-            if !peer_to.is_online() {
-                return Err(anyhow!("Peer {} is offline", &encoded_public_key_to));
-            }
+    /// Queues a verified joiner. The optimize loop overwrites `peers` with its own copy at the end of an
+    /// epoch, so joiners are merged from this queue then rather than inserted directly.
+    pub fn add_pending_join(&mut self, peer: Peer) -> Result<bool, Error> {
+        let id = &peer.encoded_public_key;
+        if *id == self.encoded_public_key
+            || self.peers.contains_key(id)
+            || self.pending_joins.iter().any(|pending| pending.encoded_public_key == *id)
+        {
+            return Ok(false);
+        }
+        if self.pending_joins.len() >= MAX_PENDING_JOINS {
+            return Err(Error::Busy("too many joins pending until the end of the epoch".to_string()));
+        }
+        self.pending_joins.push(peer);
+        Ok(true)
+    }
 
-            Ok(peer_to.best_endpoint.clone())
-        } else {
-            return Err(anyhow!("Peer {} is not in the list", &encoded_public_key_to));
+    /// Issues a nonce for `POST /join`. Outstanding challenges are never dropped to make room, so that
+    /// a flood of requests cannot cancel handshakes in progress; new ones are refused instead.
+    pub fn issue_join_challenge(&mut self) -> Result<JoinChallenge, Error> {
+        let now = now_ms();
+        self.join_challenges.retain(|_, expires_at| *expires_at > now);
+        if self.join_challenges.len() >= MAX_JOIN_CHALLENGES {
+            return Err(Error::Busy("too many outstanding join challenges".to_string()));
         }
+
+        let nonce = hex::encode(rand::random::<[u8; 32]>());
+        self.join_challenges.insert(nonce.clone(), now + JOIN_CHALLENGE_TTL_MS);
+        Ok(JoinChallenge { nonce })
     }
 
-    pub fn estimate(&self, encoded_public_key_from: String, encoded_public_key_to: String) -> Result<f64> {
+    /// Checks a join request against an outstanding challenge and returns the peer it describes.
+    /// The caller is still expected to check that the peer is reachable before adding it.
+    pub fn verify_join_request(&mut self, request: &JoinRequest) -> Result<Peer> {
+        let expires_at = self.join_challenges.remove(&request.nonce)
+            .ok_or(anyhow!("Unknown join challenge"))?;
+        if expires_at <= now_ms() {
+            return Err(anyhow!("Join challenge expired"));
+        }
+        if request.public_key == self.encoded_public_key {
+            return Err(anyhow!("Cannot join self"));
+        }
+        if request.fingerprint != self.parameters.fingerprint() {
+            return Err(anyhow!("Parameter fingerprint mismatch"));
+        }
+
+        let signature = hex::decode(&request.signature)
+            .map_err(|err| anyhow!("Malformed signature: {:?}", err))?;
+        signing::verify(&request.public_key, &request.signing_payload(&self.encoded_public_key), &signature)?;
+
+        // anyone can sign with a fresh key, so only nodes of the directory may join, and they are only
+        // probed on the endpoints it lists for them
+        let known = self.directory.lookup(&request.public_key)?;
+        let endpoints = request
+            .endpoints
+            .iter()
+            .filter(|endpoint| known.contains(endpoint))
+            .cloned()
+            .collect::<Vec<String>>();
+        if endpoints.is_empty() {
+            return Err(anyhow!("None of the endpoints of {} is in the directory", &request.public_key));
+        }
+        Peer::with_endpoints(request.public_key.clone(), endpoints)
+    }
+
+    /// Fails unless `encoded_public_key` is us or an online peer.
+    fn ensure_online(&self, encoded_public_key: &String) -> Result<(), Error> {
+        if *encoded_public_key == self.encoded_public_key {
+            return Ok(());
+        }
+        match self.peers.get(encoded_public_key) {
+            Some(peer) if peer.is_online() => Ok(()),
+            Some(_) => Err(Error::PeerOffline(encoded_public_key.clone())),
+            None => Err(Error::UnknownPeer(encoded_public_key.clone())),
+        }
+    }
+
+    pub fn get_best_endpoint_to(&self, encoded_public_key_to: String) -> Result<String, Error> {
+        let peer_to = self.peers
+            .get(&encoded_public_key_to)
+            .ok_or(Error::UnknownPeer(encoded_public_key_to.clone()))?;
+        self.ensure_online(&encoded_public_key_to)?;
+        if let Some(stats) = self.telemetry.get(&encoded_public_key_to) {
+            if stats.loss_rate > self.parameters.max_loss_rate {
+                return Err(Error::NotAvailable(format!(
+                    "link to {} loses {:.0}% of probes",
+                    &encoded_public_key_to,
+                    stats.loss_rate * 100.0
+                )));
+            }
+        }
+
+        Ok(peer_to.best_endpoint.clone())
+    }
+
+    pub fn estimate(&self, encoded_public_key_from: String, encoded_public_key_to: String) -> Result<Estimate, Error> {
         // ensure both of them are online
-        if let Some(peer_from) = self.peers.get(&encoded_public_key_from) {
-            if !peer_from.is_online() {
-                return Err(anyhow!("Peer {} is offline", &encoded_public_key_from));
+        self.ensure_online(&encoded_public_key_from)?;
+        self.ensure_online(&encoded_public_key_to)?;
+
+        let resolved_peer_from = self.resolved.get(&encoded_public_key_from)
+            .ok_or(Error::NotAvailable(format!("peer {} is not resolved", &encoded_public_key_from)))?;
+        let resolved_peer_to = self.resolved.get(&encoded_public_key_to)
+            .ok_or(Error::NotAvailable(format!("peer {} is not resolved", &encoded_public_key_to)))?;
+
+        let rtt_ms = self.parameters.distance_model().distance(&resolved_peer_from, &resolved_peer_to);
+        let confidence = match self.parameters.optimize_mode {
+            OptimizeMode::GradientDescent | OptimizeMode::MatrixFactorization => None,
+            OptimizeMode::Vivaldi => {
+                let error_from = self.errors.get(&encoded_public_key_from).cloned().unwrap_or(1.0);
+                let error_to = self.errors.get(&encoded_public_key_to).cloned().unwrap_or(1.0);
+                Some(vivaldi::confidence(error_from, error_to))
             }
+        };
+
+        // the link quality is only known for links we probe ourselves
+        let link = if encoded_public_key_from == self.encoded_public_key {
+            self.telemetry.get(&encoded_public_key_to)
+        } else if encoded_public_key_to == self.encoded_public_key {
+            self.telemetry.get(&encoded_public_key_from)
         } else {
-            return Err(anyhow!("Peer {} is not in the list", &encoded_public_key_from));
+            None
         }
+        .filter(|stats| stats.samples > 0);
 
-        if let Some(peer_to) = self.peers.get(&encoded_public_key_to) {
-            if !peer_to.is_online() {
-                return Err(anyhow!("Peer {} is offline", &encoded_public_key_to));
+        Ok(Estimate {
+            rtt_ms,
+            confidence,
+            loss_rate: link.map(|stats| stats.loss_rate),
+            jitter_ms: link.map(|stats| stats.jitter_ms),
+        })
+    }
+
+    pub fn estimate_batch(&self, request: EstimateBatchRequest) -> Result<Vec<EstimateBatchEntry>, Error> {
+        if request.pairs.is_empty() && request.from.is_none() {
+            return Err(Error::BadRequest("a batch needs pairs or a source and targets".to_string()));
+        }
+
+        let max_batch_pairs = self.parameters.max_batch_pairs as usize;
+        let targets = if request.from.is_some() { request.targets.len() } else { 0 };
+        if request.pairs.len().saturating_add(targets) > max_batch_pairs {
+            return Err(Error::BadRequest(format!("a batch must have at most {} pairs", max_batch_pairs)));
+        }
+
+        let mut pairs = request.pairs;
+        if let Some(from) = request.from {
+            pairs.extend(request.targets.into_iter().map(|to| EstimatePair { from: from.clone(), to }));
+        }
+        Ok(pairs
+            .into_iter()
+            .map(|pair| {
+                let (estimate, error) = match self.estimate(pair.from.clone(), pair.to.clone()) {
+                    Ok(estimate) => (Some(estimate), None),
+                    Err(err) => (None, Some(err.reply())),
+                };
+                EstimateBatchEntry { from: pair.from, to: pair.to, estimate, error }
+            })
+            .collect())
+    }
+
+    /// Indexes the coordinates of us and our online peers for `nearest`. Dot products are not a metric,
+    /// so in matrix factorization mode there is no index and queries scan every coordinate.
+    pub fn rebuild_nearest_index(&mut self) {
+        let model = self.parameters.distance_model();
+        if !model.is_metric() {
+            self.nearest_index = None;
+            return;
+        }
+        let points = self
+            .resolved
+            .iter()
+            .filter(|(id, _)| self.ensure_online(id).is_ok())
+            .map(|(id, coord)| (id.clone(), coord.clone()))
+            .collect::<Vec<(String, Vec<f64>)>>();
+        self.nearest_index = Some(VpTree::build(points, model.as_ref()));
+    }
+
+    /// Online nodes ranked by predicted RTT from a peer (excluded from the result) or a coordinate.
+    pub fn nearest(&self, request: NearestRequest) -> Result<Vec<Neighbor>, Error> {
+        let (source, coord) = match (request.peer, request.coord) {
+            (Some(peer), None) => {
+                if peer != self.encoded_public_key && !self.peers.contains_key(&peer) {
+                    return Err(Error::UnknownPeer(peer));
+                }
+                let coord = self.resolved
+                    .get(&peer)
+                    .cloned()
+                    .ok_or(Error::NotAvailable(format!("peer {} is not resolved", &peer)))?;
+                (Some(peer), coord)
+            }
+            (None, Some(coord)) => {
+                if coord.len() != self.parameters.vector_len() {
+                    return Err(Error::BadRequest(format!(
+                        "coordinates have {} components",
+                        self.parameters.vector_len()
+                    )));
+                }
+                (None, coord)
             }
+            _ => return Err(Error::BadRequest("nearest needs either a peer or a coordinate".to_string())),
+        };
+
+        let k = request.k.unwrap_or(NEAREST_DEFAULT_K);
+        let model = self.parameters.distance_model();
+        // the index is built once per epoch, peers may have gone offline since
+        let filter = |id: &str| Some(id) != source.as_deref() && self.ensure_online(&id.to_string()).is_ok();
+        let ranked = match &self.nearest_index {
+            Some(index) => index.nearest(&coord, k, model.as_ref(), filter),
+            None => nearest::linear_scan(self.resolved.iter(), &coord, k, model.as_ref(), filter),
+        };
+        Ok(ranked.into_iter().map(|(id, rtt_ms)| Neighbor { id, rtt_ms }).collect())
+    }
+
+    /// Only links from or to us are measured, so one side has to be this node. The measurement is
+    /// the download from the peer and is used for both directions.
+    pub fn estimate_bandwidth(
+        &self,
+        encoded_public_key_from: String,
+        encoded_public_key_to: String,
+    ) -> Result<BandwidthStats, Error> {
+        let peer_id = if encoded_public_key_from == self.encoded_public_key {
+            encoded_public_key_to
+        } else if encoded_public_key_to == self.encoded_public_key {
+            encoded_public_key_from
         } else {
-            return Err(anyhow!("Peer {} is not in the list", &encoded_public_key_to));
+            return Err(Error::BadRequest(format!(
+                "bandwidth is only measured on links of {}",
+                &self.encoded_public_key
+            )));
+        };
+        if !self.peers.contains_key(&peer_id) {
+            return Err(Error::UnknownPeer(peer_id));
         }
 
-        let resolved_peer_from = self.resolved.get(&encoded_public_key_from)
-            .ok_or(anyhow!("Peer {} is not resolved", &encoded_public_key_from))?;
-        let resolved_peer_to = self.resolved.get(&encoded_public_key_to)
-            .ok_or(anyhow!("Peer {} is not resolved", &encoded_public_key_to))?;
+        self.bandwidth
+            .get(&peer_id)
+            .cloned()
+            .ok_or(Error::NotAvailable(format!("bandwidth to {} has not been measured", &peer_id)))
+    }
+
+    pub fn history_since(&self, encoded_public_key: &String, since: u64) -> Result<Vec<HistorySample>, Error> {
+        if !self.peers.contains_key(encoded_public_key) {
+            return Err(Error::UnknownPeer(encoded_public_key.clone()));
+        }
+        self.history
+            .get(encoded_public_key)
+            .map(|history| history.since(since))
+            .ok_or(Error::NotAvailable(format!("peer {} has not been probed", encoded_public_key)))
+    }
 
-        Ok(euclidean_distance(&resolved_peer_from, &resolved_peer_to))
+    pub fn clock_estimate(&self, encoded_public_key: &String) -> Result<ClockEstimate, Error> {
+        self.peers
+            .get(encoded_public_key)
+            .ok_or(Error::UnknownPeer(encoded_public_key.clone()))?
+            .clock
+            .clone()
+            .ok_or(Error::NotAvailable(format!("clock of {} has not been estimated", encoded_public_key)))
+    }
+
+    pub fn clock_estimates(&self) -> HashMap<String, ClockEstimate> {
+        self.peers
+            .iter()
+            .filter_map(|(id, peer)| peer.clock.clone().map(|clock| (id.clone(), clock)))
+            .collect()
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            tcp_echo_port_offset: self.parameters.tcp_echo_port_offset,
+        }
+    }
+
+    pub fn signed_resolved(&self) -> Result<SignedResolved> {
+        let mut signed = SignedResolved {
+            epoch: self.status.epoch,
+            timestamp: now_ms(),
+            public_key: self.encoded_public_key.clone(),
+            resolved: self.resolved.clone(),
+            error: self.errors.get(&self.encoded_public_key).cloned().unwrap_or(1.0),
+            telemetry: telemetry::rtts(&self.telemetry),
+            signature: String::new(),
+        };
+        signed.signature = hex::encode(signing::sign(&signed.signing_payload())?);
+
+        Ok(signed)
+    }
+
+    /// Drops everything we keep about peers that are gone, as well as resolved entries that were not
+    /// refreshed and links that did not answer within their TTL. Our own entries are never dropped.
+    /// Returns the number of telemetry and resolved entries removed.
+    pub fn collect_garbage(&mut self, now: u64) -> (u64, u64) {
+        let me = &self.encoded_public_key;
+        let peers = &self.peers;
+        let telemetry_ttl_ms = self.parameters.telemetry_ttl_ms;
+        let resolved_ttl_ms = self.parameters.resolved_ttl_ms;
+
+        // entries restored from a state without timestamps start their TTL now
+        for id in self.resolved.keys() {
+            self.resolved_at.entry(id.clone()).or_insert(now);
+        }
+        let resolved_at = &self.resolved_at;
+        let resolved_len = self.resolved.len();
+        self.resolved.retain(|id, _| {
+            id == me
+                || (peers.contains_key(id)
+                    && resolved_at.get(id).map_or(false, |at| now.saturating_sub(*at) <= resolved_ttl_ms))
+        });
+
+        // the optimizers expect a coordinate for every measured link, and failed probes keep the last
+        // RTT around without confirming it, so links expire on their last successful probe
+        let resolved = &self.resolved;
+        let telemetry_len = self.telemetry.len();
+        self.telemetry.retain(|id, stats| {
+            id == me || (resolved.contains_key(id) && now.saturating_sub(stats.last_seen) <= telemetry_ttl_ms)
+        });
+
+        self.resolved_at.retain(|id, _| resolved.contains_key(id));
+        self.inbound_telemetry.retain(|id, _| peers.contains_key(id));
+        self.errors.retain(|id, _| id == me || peers.contains_key(id));
+        self.bandwidth.retain(|id, _| peers.contains_key(id));
+        self.history.retain(|id, _| peers.contains_key(id));
+
+        (
+            (telemetry_len - self.telemetry.len()) as u64,
+            (resolved_len - self.resolved.len()) as u64,
+        )
     }
 
     pub fn start_optimize(&mut self) {
@@ -261,3 +1030,387 @@ impl Probe {
         self.status.is_optimizing = false;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use schnorrkel::{ExpansionMode, Keypair, MiniSecretKey};
+
+    fn keypair(seed: u8) -> Keypair {
+        MiniSecretKey::from_bytes(&[seed; 32]).unwrap().expand_to_keypair(ExpansionMode::Ed25519)
+    }
+
+    fn public_key(keypair: &Keypair) -> String {
+        hex::encode(keypair.public.to_bytes())
+    }
+
+    fn signed(keypair: &Keypair, epoch: u64, timestamp: u64) -> SignedResolved {
+        let mut signed = SignedResolved {
+            epoch,
+            timestamp,
+            public_key: public_key(keypair),
+            resolved: [("a".to_string(), vec![1.0, 2.0]), ("b".to_string(), vec![3.0, 4.0])].into_iter().collect(),
+            error: 0.5,
+            telemetry: [("a".to_string(), 12.5)].into_iter().collect(),
+            signature: String::new(),
+        };
+        signed.signature = hex::encode(signing::sign_with(keypair, &signed.signing_payload()));
+        signed
+    }
+
+    fn peer(keypair: &Keypair) -> Peer {
+        Peer::with_endpoints(public_key(keypair), vec!["127.0.0.1:8000".to_string()]).unwrap()
+    }
+
+    fn parameters() -> ProbeParameters {
+        ProbeParameters {
+            resolved_ttl_ms: 60 * 1000,
+            ..Default::default()
+        }
+    }
+
+    // a probe whose directory only knows `keypair(2)`
+    fn probe() -> Probe {
+        let directory = [(public_key(&keypair(2)), vec!["10.0.0.2:8000".to_string()])].into_iter().collect();
+        Probe::with_parameters(public_key(&keypair(1)), Vec::new(), Directory::from_entries(directory), parameters())
+    }
+
+    fn join_request(probe: &mut Probe, joiner: &Keypair, endpoints: &[&str]) -> JoinRequest {
+        let mut request = JoinRequest {
+            public_key: public_key(joiner),
+            endpoints: endpoints.iter().map(|endpoint| endpoint.to_string()).collect(),
+            fingerprint: probe.parameters.fingerprint(),
+            nonce: probe.issue_join_challenge().unwrap().nonce,
+            signature: String::new(),
+        };
+        sign_join_request(probe, joiner, &mut request);
+        request
+    }
+
+    fn sign_join_request(probe: &Probe, joiner: &Keypair, request: &mut JoinRequest) {
+        let payload = request.signing_payload(&probe.encoded_public_key);
+        request.signature = hex::encode(signing::sign_with(joiner, &payload));
+    }
+
+    #[test]
+    fn signing_payload_covers_every_field_in_a_stable_order() {
+        let keypair = keypair(1);
+        let a = signed(&keypair, 3, 1000);
+        let mut b = a.clone();
+        let mut entries = a.resolved.clone().into_iter().collect::<Vec<(String, Vec<f64>)>>();
+        entries.reverse();
+        b.resolved = entries.into_iter().collect();
+        assert_eq!(a.signing_payload(), b.signing_payload());
+
+        let mut changed = a.clone();
+        changed.timestamp += 1;
+        assert_ne!(a.signing_payload(), changed.signing_payload());
+        let mut changed = a.clone();
+        changed.epoch += 1;
+        assert_ne!(a.signing_payload(), changed.signing_payload());
+        let mut changed = a.clone();
+        changed.telemetry.insert("a".to_string(), 12.6);
+        assert_ne!(a.signing_payload(), changed.signing_payload());
+    }
+
+    #[test]
+    fn keeps_an_rtt_window_per_endpoint() {
+        let parameters = ProbeParameters {
+            rtt_window: 3,
+            rtt_percentile: 0.5,
+            ..Default::default()
+        };
+        let mut peer = peer(&keypair(2));
+        peer.record_rtt_window("a", &[10.0, 10.0], &parameters);
+        peer.record_rtt_window("b", &[50.0, 50.0, 50.0], &parameters);
+        peer.record_rtt_window("a", &[30.0, 40.0], &parameters);
+        assert_eq!(peer.rtt_windows["a"], [10.0, 30.0, 40.0]);
+        assert_eq!(peer.rtt_window_percentile("a", &parameters), 30.0);
+        assert_eq!(peer.rtt_window_percentile("b", &parameters), 50.0);
+
+        let directory = Directory::from_entries([(peer.encoded_public_key.clone(), vec!["a".to_string()])].into());
+        peer.update_endpoints(&directory).unwrap();
+        assert_eq!(peer.rtt_windows.keys().collect::<Vec<_>>(), ["a"]);
+    }
+
+    #[test]
+    fn accepts_a_signed_map() {
+        let keypair = keypair(1);
+        let mut peer = peer(&keypair);
+        let signed = signed(&keypair, 3, 1000);
+        assert!(peer.accept_resolved(signed, 2000, &parameters()).is_ok());
+        assert_eq!(peer.resolved_timestamp, Some(1000));
+    }
+
+    #[test]
+    fn rejects_a_bad_signature() {
+        let keypair = keypair(1);
+        let mut peer = peer(&keypair);
+        let mut signed = signed(&keypair, 3, 1000);
+        signed.resolved.insert("a".to_string(), vec![100.0, 2.0]);
+        assert!(peer.accept_resolved(signed.clone(), 2000, &parameters()).is_err());
+        signed.signature = "not hex".to_string();
+        assert!(peer.accept_resolved(signed, 2000, &parameters()).is_err());
+        assert_eq!(peer.resolved_timestamp, None);
+    }
+
+    #[test]
+    fn rejects_a_wrong_key() {
+        let mut peer = peer(&keypair(1));
+        // validly signed, but by someone else
+        assert!(peer.accept_resolved(signed(&keypair(2), 3, 1000), 2000, &parameters()).is_err());
+        // claiming to be the peer
+        let mut signed = signed(&keypair(2), 3, 1000);
+        signed.public_key = peer.encoded_public_key.clone();
+        assert!(peer.accept_resolved(signed, 2000, &parameters()).is_err());
+    }
+
+    #[test]
+    fn rejects_a_replay() {
+        let keypair = keypair(1);
+        let mut peer = peer(&keypair);
+        let old = signed(&keypair, 3, 1000);
+        let new = signed(&keypair, 4, 5000);
+        assert!(peer.accept_resolved(old.clone(), 1000, &parameters()).is_ok());
+        assert!(peer.accept_resolved(new.clone(), 5000, &parameters()).is_ok());
+        assert!(peer.accept_resolved(old, 6000, &parameters()).is_err());
+        // the same map again brings nothing new but is not a replay
+        assert!(peer.accept_resolved(new, 6000, &parameters()).is_ok());
+        assert_eq!(peer.resolved_timestamp, Some(5000));
+    }
+
+    #[test]
+    fn rejects_a_stale_map() {
+        let keypair = keypair(1);
+        let mut peer = peer(&keypair);
+        let signed = signed(&keypair, 3, 1000);
+        assert!(peer.accept_resolved(signed, 1000 + 61 * 1000, &parameters()).is_err());
+    }
+
+    #[test]
+    fn accepts_a_restarted_peer() {
+        let keypair = keypair(1);
+        let mut peer = peer(&keypair);
+        assert!(peer.accept_resolved(signed(&keypair, 100, 1000), 1000, &parameters()).is_ok());
+        // the epoch starts over after a restart, the signing time does not
+        assert!(peer.accept_resolved(signed(&keypair, 0, 2000), 2000, &parameters()).is_ok());
+        assert_eq!(peer.resolved_timestamp, Some(2000));
+    }
+
+    #[test]
+    fn accepts_a_join_on_directory_endpoints() {
+        let mut probe = probe();
+        let request = join_request(&mut probe, &keypair(2), &["10.0.0.2:8000", "192.0.2.1:80"]);
+        let peer = probe.verify_join_request(&request).unwrap();
+        assert_eq!(peer.encoded_public_key, public_key(&keypair(2)));
+        assert_eq!(peer.endpoints, vec!["10.0.0.2:8000".to_string()]);
+    }
+
+    #[test]
+    fn rejects_a_reused_nonce() {
+        let mut probe = probe();
+        let request = join_request(&mut probe, &keypair(2), &["10.0.0.2:8000"]);
+        assert!(probe.verify_join_request(&request).is_ok());
+        assert!(probe.verify_join_request(&request).is_err());
+    }
+
+    #[test]
+    fn rejects_an_expired_nonce() {
+        let mut probe = probe();
+        let request = join_request(&mut probe, &keypair(2), &["10.0.0.2:8000"]);
+        probe.join_challenges.insert(request.nonce.clone(), now_ms() - 1);
+        assert!(probe.verify_join_request(&request).is_err());
+    }
+
+    #[test]
+    fn rejects_a_fingerprint_mismatch() {
+        let mut probe = probe();
+        let mut request = join_request(&mut probe, &keypair(2), &["10.0.0.2:8000"]);
+        request.fingerprint = "other".to_string();
+        sign_join_request(&probe, &keypair(2), &mut request);
+        assert!(probe.verify_join_request(&request).is_err());
+    }
+
+    #[test]
+    fn rejects_a_bad_join_signature() {
+        let mut probe = probe();
+        let mut request = join_request(&mut probe, &keypair(2), &["10.0.0.2:8000"]);
+        sign_join_request(&probe, &keypair(3), &mut request);
+        assert!(probe.verify_join_request(&request).is_err());
+
+        let mut request = join_request(&mut probe, &keypair(2), &["10.0.0.2:8000"]);
+        request.endpoints.push("192.0.2.1:80".to_string());
+        assert!(probe.verify_join_request(&request).is_err());
+    }
+
+    #[test]
+    fn rejects_joiners_outside_the_directory() {
+        let mut probe = probe();
+        let request = join_request(&mut probe, &keypair(3), &["10.0.0.2:8000"]);
+        assert!(probe.verify_join_request(&request).is_err());
+        let request = join_request(&mut probe, &keypair(2), &["192.0.2.1:80"]);
+        assert!(probe.verify_join_request(&request).is_err());
+    }
+
+    #[test]
+    fn refuses_challenges_when_full() {
+        let mut probe = probe();
+        let nonces = (0..MAX_JOIN_CHALLENGES)
+            .map(|_| probe.issue_join_challenge().unwrap().nonce)
+            .collect::<Vec<String>>();
+        assert!(matches!(probe.issue_join_challenge(), Err(Error::Busy(_))));
+        assert!(nonces.iter().all(|nonce| probe.join_challenges.contains_key(nonce)));
+    }
+
+    #[test]
+    fn caps_pending_joins() {
+        let mut probe = probe();
+        for i in 0..MAX_PENDING_JOINS {
+            let peer = Peer::with_endpoints(format!("peer{}", i), vec!["10.0.0.2:8000".to_string()]).unwrap();
+            assert!(probe.add_pending_join(peer).unwrap());
+        }
+        let again = Peer::with_endpoints("peer0".to_string(), vec!["10.0.0.2:8000".to_string()]).unwrap();
+        assert!(!probe.add_pending_join(again).unwrap());
+        let peer = Peer::with_endpoints("peer".to_string(), vec!["10.0.0.2:8000".to_string()]).unwrap();
+        assert!(matches!(probe.add_pending_join(peer), Err(Error::Busy(_))));
+    }
+
+    #[test]
+    fn collects_stale_and_departed_entries() {
+        let now = 3_600_000;
+        let mut probe = probe();
+        probe.parameters.telemetry_ttl_ms = 30_000;
+        let me = probe.encoded_public_key.clone();
+        probe.resolved_at.insert(me.clone(), 0);
+        probe.telemetry.get_mut(&me).unwrap().last_seen = 0;
+
+        // `fresh` is refreshed, `stale` is not, `gone` left the peers, `silent` stopped answering and
+        // `restored` comes from a state saved without timestamps
+        for id in ["fresh", "stale", "silent", "restored"] {
+            probe.peers.insert(id.to_string(), Peer::with_endpoints(id.to_string(), vec![id.to_string()]).unwrap());
+        }
+        for (id, resolved_at, last_seen) in [
+            ("fresh", Some(now), now),
+            ("stale", Some(now - 61_000), now),
+            ("gone", Some(now), now),
+            ("silent", Some(now), now - 31_000),
+            ("restored", None, now),
+        ] {
+            probe.resolved.insert(id.to_string(), vec![0.0; 3]);
+            if let Some(at) = resolved_at {
+                probe.resolved_at.insert(id.to_string(), at);
+            }
+            let stats = LinkStats { rtt_ms: Some(10.0), last_seen, ..Default::default() };
+            probe.telemetry.insert(id.to_string(), stats);
+            probe.errors.insert(id.to_string(), 1.0);
+            probe.inbound_telemetry.insert(id.to_string(), 10.0);
+            probe.bandwidth.insert(id.to_string(), BandwidthStats::default());
+            probe.history.insert(id.to_string(), History::default());
+        }
+
+        assert_eq!(probe.collect_garbage(now), (3, 2));
+
+        let sorted = |keys: Vec<&String>| {
+            let mut keys = keys.into_iter().cloned().collect::<Vec<String>>();
+            keys.sort();
+            keys
+        };
+        let mut resolved = vec![me.clone(), "fresh".to_string(), "restored".to_string(), "silent".to_string()];
+        resolved.sort();
+        assert_eq!(sorted(probe.resolved.keys().collect()), resolved);
+        assert_eq!(sorted(probe.resolved_at.keys().collect()), resolved);
+        assert_eq!(probe.resolved_at["restored"], now);
+        let mut telemetry = vec![me.clone(), "fresh".to_string(), "restored".to_string()];
+        telemetry.sort();
+        assert_eq!(sorted(probe.telemetry.keys().collect()), telemetry);
+        assert!(!probe.errors.contains_key("gone") && probe.errors.contains_key(&me));
+        assert!(!probe.inbound_telemetry.contains_key("gone") && probe.inbound_telemetry.contains_key("stale"));
+        assert!(!probe.bandwidth.contains_key("gone") && !probe.history.contains_key("gone"));
+    }
+
+    // `a` is online and resolved, `off` is offline and `new` is online but not resolved yet
+    fn estimating_probe() -> Probe {
+        let mut probe = probe();
+        let me = probe.encoded_public_key.clone();
+        probe.resolved.insert(me, vec![0.0, 0.0, 0.0]);
+        for (id, state) in [("a", PeerState::Online), ("off", PeerState::Offline), ("new", PeerState::Online)] {
+            let mut peer = Peer::with_endpoints(id.to_string(), vec![id.to_string()]).unwrap();
+            peer.detector.state = state;
+            probe.peers.insert(id.to_string(), peer);
+        }
+        probe.resolved.insert("a".to_string(), vec![3.0, 4.0, 0.0]);
+        probe.resolved.insert("off".to_string(), vec![1.0, 0.0, 0.0]);
+        probe.parameters.max_batch_pairs = 4;
+        probe
+    }
+
+    fn pair(from: &str, to: &str) -> EstimatePair {
+        EstimatePair { from: from.to_string(), to: to.to_string() }
+    }
+
+    fn error_code(entry: &EstimateBatchEntry) -> Option<&str> {
+        entry.error.as_ref().map(|error| error.error.as_str())
+    }
+
+    #[test]
+    fn estimates_each_pair_on_its_own() {
+        let probe = estimating_probe();
+        let me = probe.encoded_public_key.clone();
+        let request = EstimateBatchRequest {
+            pairs: vec![pair(&me, "a"), pair("a", "off"), pair(&me, "new"), pair("ghost", "a")],
+            ..Default::default()
+        };
+        let entries = probe.estimate_batch(request).unwrap();
+
+        assert_eq!(entries.iter().map(|entry| entry.to.as_str()).collect::<Vec<_>>(), ["a", "off", "new", "a"]);
+        assert_eq!(entries[0].estimate.as_ref().map(|estimate| estimate.rtt_ms), Some(5.0));
+        assert_eq!(error_code(&entries[0]), None);
+        assert_eq!(error_code(&entries[1]), Some("peer_offline"));
+        assert_eq!(error_code(&entries[2]), Some("not_available"));
+        assert_eq!(error_code(&entries[3]), Some("unknown_peer"));
+        assert!(entries[1..].iter().all(|entry| entry.estimate.is_none()));
+    }
+
+    #[test]
+    fn estimates_from_a_source_to_targets_after_pairs() {
+        let probe = estimating_probe();
+        let me = probe.encoded_public_key.clone();
+        let request = EstimateBatchRequest {
+            pairs: vec![pair("a", &me)],
+            from: Some("a".to_string()),
+            targets: vec![me.clone(), "a".to_string()],
+        };
+        let entries = probe.estimate_batch(request).unwrap();
+
+        let pairs = entries.iter().map(|entry| (entry.from.as_str(), entry.to.as_str())).collect::<Vec<_>>();
+        assert_eq!(pairs, [("a", me.as_str()), ("a", me.as_str()), ("a", "a")]);
+        let rtts = entries.iter().map(|entry| entry.estimate.as_ref().unwrap().rtt_ms).collect::<Vec<_>>();
+        assert_eq!(rtts, [5.0, 5.0, 0.0]);
+    }
+
+    #[test]
+    fn caps_the_pairs_of_a_batch() {
+        let probe = estimating_probe();
+        let me = probe.encoded_public_key.clone();
+        let empty = EstimateBatchRequest::default();
+        assert!(matches!(probe.estimate_batch(empty), Err(Error::BadRequest(_))));
+
+        // pairs and targets count together
+        let request = |pairs: usize, targets: usize| EstimateBatchRequest {
+            pairs: vec![pair(&me, "a"); pairs],
+            from: Some(me.clone()),
+            targets: vec!["a".to_string(); targets],
+        };
+        assert_eq!(probe.estimate_batch(request(2, 2)).unwrap().len(), 4);
+        assert!(matches!(probe.estimate_batch(request(2, 3)), Err(Error::BadRequest(_))));
+        assert!(matches!(probe.estimate_batch(request(0, 5)), Err(Error::BadRequest(_))));
+
+        // targets without a source are ignored rather than counted
+        let request = EstimateBatchRequest {
+            pairs: vec![pair(&me, "a")],
+            from: None,
+            targets: vec!["a".to_string(); 8],
+        };
+        assert_eq!(probe.estimate_batch(request).unwrap().len(), 1);
+    }
+}
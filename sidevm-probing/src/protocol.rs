@@ -0,0 +1,428 @@
+use scale::{Decode, DecodeAll, Encode};
+
+use crate::command::{self, Command};
+use crate::error::{Error, ErrorReply};
+use crate::types::{
+    BandwidthStats, ClockEstimate, Estimate, EstimateBatchEntry, EstimateBatchRequest, EstimatePair, HistorySample,
+    NearestRequest, Neighbor, ProbeStatus, SignedResolved,
+};
+
+// SCALE protocol of pink queries: the payload is a `VersionedRequest` and the reply a
+// `VersionedResponse` of the same version. Contracts have no floating point, so every real number is
+// sent as a `Fixed` with 6 decimals, the same scaling as the `sidevm_probing::param::*` values.
+
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// A real number multiplied by 1e6.
+pub type Fixed = i64;
+
+pub fn fixed(value: f64) -> Fixed {
+    (value * 1e6).round() as Fixed
+}
+
+pub fn unfixed(value: Fixed) -> f64 {
+    value as f64 / 1e6
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "scale-info", derive(scale_info::TypeInfo))]
+pub enum VersionedRequest {
+    #[codec(index = 1)]
+    V1(Request),
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "scale-info", derive(scale_info::TypeInfo))]
+pub enum VersionedResponse {
+    #[codec(index = 1)]
+    V1(Result<Response, ErrorReply>),
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "scale-info", derive(scale_info::TypeInfo))]
+pub enum Request {
+    Echo(Vec<u8>),
+    Resolved,
+    Estimate { from: String, to: String },
+    EstimateBandwidth { from: String, to: String },
+    BestEndpoint { to: String },
+    History { peer: String, since: u64 },
+    ClockOffset { peer: String },
+    Status,
+    // down to `LoadApp`, commands that change the node, refused over queries with `Forbidden`
+    AddPeer { id: String },
+    StartOptimize,
+    StopOptimize,
+    SetDirectory { entries: Vec<(String, Vec<String>)> },
+    UpdatePeerEndpoints { id: String, endpoints: Vec<String> },
+    RemovePeerEndpoints { id: String },
+    SaveApp,
+    LoadApp,
+    // pairs first, then `from` to each of `targets`
+    EstimateBatch { pairs: Vec<(String, String)>, from: Option<String>, targets: Vec<String> },
+    // either a peer or a coordinate
+    Nearest { peer: Option<String>, coord: Option<Vec<Fixed>>, k: Option<u32> },
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "scale-info", derive(scale_info::TypeInfo))]
+pub enum Response {
+    Echo(Vec<u8>),
+    Resolved(ResolvedReply),
+    Estimate(EstimateReply),
+    EstimateBandwidth(BandwidthReply),
+    BestEndpoint(String),
+    History(Vec<HistorySampleReply>),
+    ClockOffset(ClockReply),
+    Status(StatusReply),
+    Done,
+    EstimateBatch(Vec<EstimateBatchReply>),
+    Nearest(Vec<NeighborReply>),
+}
+
+/// The node's resolved map. The signature covers the exact values served on `/resolved`, not the
+/// fixed-point ones.
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "scale-info", derive(scale_info::TypeInfo))]
+pub struct ResolvedReply {
+    pub epoch: u64,
+    pub timestamp: u64,
+    pub public_key: String,
+    // sorted by peer id
+    pub resolved: Vec<(String, Vec<Fixed>)>,
+    pub error: Fixed,
+    pub telemetry: Vec<(String, Fixed)>,
+    pub signature: String,
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "scale-info", derive(scale_info::TypeInfo))]
+pub struct EstimateReply {
+    pub rtt_ms: Fixed,
+    pub confidence: Option<Fixed>,
+    pub loss_rate: Option<Fixed>,
+    pub jitter_ms: Option<Fixed>,
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "scale-info", derive(scale_info::TypeInfo))]
+pub struct EstimateBatchReply {
+    pub from: String,
+    pub to: String,
+    pub estimate: Result<EstimateReply, ErrorReply>,
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "scale-info", derive(scale_info::TypeInfo))]
+pub struct NeighborReply {
+    pub id: String,
+    pub rtt_ms: Fixed,
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "scale-info", derive(scale_info::TypeInfo))]
+pub struct BandwidthReply {
+    pub mbps: Fixed,
+    pub samples: u64,
+    pub last_measured: u64,
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "scale-info", derive(scale_info::TypeInfo))]
+pub struct HistorySampleReply {
+    pub timestamp: u64,
+    pub rtt_ms: Option<Fixed>,
+    pub samples: u32,
+    pub failures: u32,
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "scale-info", derive(scale_info::TypeInfo))]
+pub struct ClockReply {
+    pub offset_ms: Fixed,
+    pub delay_ms: Fixed,
+    pub forward_ms: Fixed,
+    pub backward_ms: Fixed,
+    pub samples: u64,
+    pub updated_at: u64,
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "scale-info", derive(scale_info::TypeInfo))]
+pub struct StatusReply {
+    pub is_optimizing: bool,
+    pub precision_ms: Fixed,
+    pub epoch: u64,
+    pub rejected_contributions: u64,
+    pub misaligned_maps: u64,
+    pub removed_peers: u64,
+    pub removed_telemetry: u64,
+    pub removed_resolved: u64,
+}
+
+impl From<&SignedResolved> for ResolvedReply {
+    fn from(signed: &SignedResolved) -> Self {
+        let mut resolved = signed
+            .resolved
+            .iter()
+            .map(|(id, coord)| (id.clone(), coord.iter().cloned().map(fixed).collect()))
+            .collect::<Vec<(String, Vec<Fixed>)>>();
+        resolved.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut telemetry = signed
+            .telemetry
+            .iter()
+            .map(|(id, rtt)| (id.clone(), fixed(*rtt)))
+            .collect::<Vec<(String, Fixed)>>();
+        telemetry.sort_by(|a, b| a.0.cmp(&b.0));
+
+        ResolvedReply {
+            epoch: signed.epoch,
+            timestamp: signed.timestamp,
+            public_key: signed.public_key.clone(),
+            resolved,
+            error: fixed(signed.error),
+            telemetry,
+            signature: signed.signature.clone(),
+        }
+    }
+}
+
+impl From<&Estimate> for EstimateReply {
+    fn from(estimate: &Estimate) -> Self {
+        EstimateReply {
+            rtt_ms: fixed(estimate.rtt_ms),
+            confidence: estimate.confidence.map(fixed),
+            loss_rate: estimate.loss_rate.map(fixed),
+            jitter_ms: estimate.jitter_ms.map(fixed),
+        }
+    }
+}
+
+impl From<&EstimateBatchEntry> for EstimateBatchReply {
+    fn from(entry: &EstimateBatchEntry) -> Self {
+        let estimate = match (&entry.estimate, &entry.error) {
+            (Some(estimate), _) => Ok(estimate.into()),
+            (None, Some(error)) => Err(error.clone()),
+            (None, None) => Err(Error::Internal("missing estimate".to_string()).reply()),
+        };
+        EstimateBatchReply { from: entry.from.clone(), to: entry.to.clone(), estimate }
+    }
+}
+
+impl From<&Neighbor> for NeighborReply {
+    fn from(neighbor: &Neighbor) -> Self {
+        NeighborReply {
+            id: neighbor.id.clone(),
+            rtt_ms: fixed(neighbor.rtt_ms),
+        }
+    }
+}
+
+impl From<&BandwidthStats> for BandwidthReply {
+    fn from(stats: &BandwidthStats) -> Self {
+        BandwidthReply {
+            mbps: fixed(stats.mbps),
+            samples: stats.samples,
+            last_measured: stats.last_measured,
+        }
+    }
+}
+
+impl From<&HistorySample> for HistorySampleReply {
+    fn from(sample: &HistorySample) -> Self {
+        HistorySampleReply {
+            timestamp: sample.timestamp,
+            rtt_ms: sample.rtt_ms.map(fixed),
+            samples: sample.samples,
+            failures: sample.failures,
+        }
+    }
+}
+
+impl From<&ClockEstimate> for ClockReply {
+    fn from(clock: &ClockEstimate) -> Self {
+        ClockReply {
+            offset_ms: fixed(clock.offset_ms),
+            delay_ms: fixed(clock.delay_ms),
+            forward_ms: fixed(clock.forward_ms),
+            backward_ms: fixed(clock.backward_ms),
+            samples: clock.samples,
+            updated_at: clock.updated_at,
+        }
+    }
+}
+
+impl From<&ProbeStatus> for StatusReply {
+    fn from(status: &ProbeStatus) -> Self {
+        StatusReply {
+            is_optimizing: status.is_optimizing,
+            precision_ms: fixed(status.precision_ms),
+            epoch: status.epoch,
+            rejected_contributions: status.rejected_contributions,
+            misaligned_maps: status.misaligned_maps,
+            removed_peers: status.removed_peers,
+            removed_telemetry: status.removed_telemetry,
+            removed_resolved: status.removed_resolved,
+        }
+    }
+}
+
+impl From<Request> for Command {
+    fn from(request: Request) -> Self {
+        match request {
+            Request::Echo(data) => Command::Echo(data),
+            Request::Resolved => Command::Resolved,
+            Request::Estimate { from, to } => Command::Estimate { from, to },
+            Request::EstimateBandwidth { from, to } => Command::EstimateBandwidth { from, to },
+            Request::BestEndpoint { to } => Command::BestEndpoint { to },
+            Request::History { peer, since } => Command::History { peer, since },
+            Request::ClockOffset { peer } => Command::ClockOffset { peer },
+            Request::Status => Command::Status,
+            Request::AddPeer { id } => Command::AddPeer { id },
+            Request::StartOptimize => Command::StartOptimize,
+            Request::StopOptimize => Command::StopOptimize,
+            Request::SetDirectory { entries } => Command::SetDirectory { entries: entries.into_iter().collect() },
+            Request::UpdatePeerEndpoints { id, endpoints } => Command::UpdatePeerEndpoints { id, endpoints },
+            Request::RemovePeerEndpoints { id } => Command::RemovePeerEndpoints { id },
+            Request::SaveApp => Command::SaveApp,
+            Request::LoadApp => Command::LoadApp,
+            Request::EstimateBatch { pairs, from, targets } => Command::EstimateBatch(EstimateBatchRequest {
+                pairs: pairs.into_iter().map(|(from, to)| EstimatePair { from, to }).collect(),
+                from,
+                targets,
+            }),
+            Request::Nearest { peer, coord, k } => Command::Nearest(NearestRequest {
+                peer,
+                coord: coord.map(|coord| coord.into_iter().map(unfixed).collect()),
+                k: k.map(|k| k as usize),
+            }),
+        }
+    }
+}
+
+impl From<command::Response> for Response {
+    fn from(response: command::Response) -> Self {
+        match response {
+            command::Response::Echo(data) => Response::Echo(data),
+            command::Response::Resolved(resolved) => Response::Resolved((&resolved).into()),
+            command::Response::Estimate(estimate) => Response::Estimate((&estimate).into()),
+            command::Response::EstimateBandwidth(stats) => Response::EstimateBandwidth((&stats).into()),
+            command::Response::BestEndpoint(endpoint) => Response::BestEndpoint(endpoint),
+            command::Response::History(history) => {
+                Response::History(history.iter().map(|sample| sample.into()).collect())
+            }
+            command::Response::ClockOffset(clock) => Response::ClockOffset((&clock).into()),
+            command::Response::Status(status) => Response::Status((&status).into()),
+            command::Response::Done => Response::Done,
+            command::Response::EstimateBatch(entries) => {
+                Response::EstimateBatch(entries.iter().map(|entry| entry.into()).collect())
+            }
+            command::Response::Nearest(neighbors) => {
+                Response::Nearest(neighbors.iter().map(|neighbor| neighbor.into()).collect())
+            }
+        }
+    }
+}
+
+/// Decodes a SCALE query payload, telling an unsupported version apart from a malformed request.
+pub fn decode_request(payload: &[u8]) -> Result<Command, Error> {
+    match payload.first() {
+        Some(&PROTOCOL_VERSION) => {}
+        Some(version) => return Err(Error::BadRequest(format!("unsupported protocol version {}", version))),
+        None => return Err(Error::BadRequest("empty query".to_string())),
+    }
+    match VersionedRequest::decode_all(&mut &payload[..]) {
+        Ok(VersionedRequest::V1(request)) => Ok(request.into()),
+        Err(err) => Err(Error::BadRequest(format!("malformed query: {}", err))),
+    }
+}
+
+pub fn encode_response(result: Result<command::Response, Error>) -> Vec<u8> {
+    VersionedResponse::V1(result.map(Response::from).map_err(|err| err.reply())).encode()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(request: Request) -> Vec<u8> {
+        VersionedRequest::V1(request).encode()
+    }
+
+    fn bad_request(payload: &[u8]) -> String {
+        match decode_request(payload) {
+            Err(Error::BadRequest(message)) => message,
+            other => panic!("expected a bad request, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn prefixes_payloads_with_the_version() {
+        assert_eq!(request(Request::Status), [PROTOCOL_VERSION, 7]);
+        assert_eq!(encode_response(Ok(command::Response::Done))[0], PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn decodes_requests_into_commands() {
+        let payload = request(Request::Estimate { from: "a".to_string(), to: "b".to_string() });
+        assert!(matches!(
+            decode_request(&payload),
+            Ok(Command::Estimate { from, to }) if from == "a" && to == "b"
+        ));
+
+        let payload = request(Request::EstimateBatch {
+            pairs: vec![("a".to_string(), "b".to_string())],
+            from: Some("c".to_string()),
+            targets: vec!["d".to_string()],
+        });
+        match decode_request(&payload) {
+            Ok(Command::EstimateBatch(batch)) => {
+                assert_eq!((batch.pairs[0].from.as_str(), batch.pairs[0].to.as_str()), ("a", "b"));
+                assert_eq!((batch.from.as_deref(), batch.targets.as_slice()), (Some("c"), &["d".to_string()][..]));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let payload = request(Request::Nearest { peer: None, coord: Some(vec![fixed(1.5), fixed(-0.25)]), k: Some(3) });
+        match decode_request(&payload) {
+            Ok(Command::Nearest(nearest)) => {
+                assert_eq!(nearest.coord, Some(vec![1.5, -0.25]));
+                assert_eq!((nearest.peer, nearest.k), (None, Some(3)));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_versions_and_malformed_payloads() {
+        let mut payload = request(Request::Status);
+        payload[0] = 2;
+        assert_eq!(bad_request(&payload), "unsupported protocol version 2");
+        assert_eq!(bad_request(&[]), "empty query");
+        assert!(bad_request(&[PROTOCOL_VERSION, 0xff]).starts_with("malformed query"));
+
+        // trailing bytes are not silently ignored
+        let mut payload = request(Request::Status);
+        payload.push(0);
+        assert!(bad_request(&payload).starts_with("malformed query"));
+    }
+
+    #[test]
+    fn encodes_replies_and_errors() {
+        let decode = |payload: Vec<u8>| match VersionedResponse::decode_all(&mut &payload[..]).unwrap() {
+            VersionedResponse::V1(result) => result,
+        };
+        let echo = decode(encode_response(Ok(command::Response::Echo(b"ping".to_vec()))));
+        assert!(matches!(echo, Ok(Response::Echo(data)) if data == b"ping"));
+
+        let err = decode(encode_response(Err(Error::Forbidden("no".to_string())))).unwrap_err();
+        assert_eq!((err.error.as_str(), err.message.as_str()), ("forbidden", "no"));
+    }
+
+    #[test]
+    fn fixed_point_keeps_six_decimals() {
+        assert_eq!(fixed(1.2345675), 1_234_568);
+        assert_eq!(fixed(-0.5), -500_000);
+        assert_eq!(unfixed(fixed(12.345678)), 12.345678);
+    }
+}
@@ -1,74 +1,83 @@
 use anyhow::{Result};
-use log::{info};
+use log::{info, warn};
 
+use crate::command::{dispatch, Command};
+use crate::error::Error;
+use crate::protocol;
 use crate::AppState;
 use crate::types;
 
+/// Reads a JSON `QueryMessage`, the original query format.
+fn json_command(payload: &[u8]) -> Result<Command, Error> {
+    let msg: types::QueryMessage = serde_json::from_slice(payload).map_err(|err| Error::BadRequest(err.to_string()))?;
+    Command::from_message(&msg.command, &msg.data)?.read_only()
+}
+
+fn is_json(payload: &[u8]) -> bool {
+    payload.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'{')
+}
+
+/// Answers one query. Every failure, including a malformed payload, is replied as an error in the
+/// query's own protocol so that a bad query never stops the loop.
+async fn handle(app_state: &AppState, payload: &[u8]) -> Vec<u8> {
+    if is_json(payload) {
+        let result = match json_command(payload) {
+            Ok(command) => dispatch(app_state, command).await.and_then(|response| response.into_body()),
+            Err(err) => Err(err),
+        };
+        result.unwrap_or_else(|err| {
+            warn!("Query failed: {}", err);
+            err.to_json().into_bytes()
+        })
+    } else {
+        let result = match protocol::decode_request(payload).and_then(Command::read_only) {
+            Ok(command) => dispatch(app_state, command).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = &result {
+            warn!("Query failed: {}", err);
+        }
+        protocol::encode_response(result)
+    }
+}
+
 pub async fn init_pink_query(app_state: AppState) -> Result<()> {
     info!("Initializing pink query...");
     loop {
         if let Some(query) = sidevm::channel::incoming_queries().next().await {
-            let payload_str = String::from_utf8_lossy(&query.payload);
-            let msg: types::QueryMessage = serde_json::from_str(&payload_str)?;
-            info!("Received host query: {:?} from: {:?}", msg, query.origin);
-            match msg.command.as_str() {
-                "echo" => {
-                    let _ = query.reply_tx.send(msg.data.as_bytes());
-                }
-                "resolved" => {
-                    let lock = app_state.lock().await;
-                    let probe = (*lock).as_ref().unwrap();
-
-                    let resolved = serde_json::to_string(&probe.resolved).unwrap();
-                    let _ = query.reply_tx.send(resolved.as_bytes());
-                }
-                "estimate" => {
-                    let estimate_request: types::QueryEstimateRequest = serde_json::from_str(&msg.data)?;
-                    let peer_id_from = estimate_request.from;
-                    let peer_id_to = estimate_request.to;
-
-                    let lock = app_state.lock().await;
-                    let probe = (*lock).as_ref().unwrap();
-                    let estimation = probe.estimate(peer_id_from.clone(), peer_id_to.clone())
-                        .unwrap_or(-1.0 as f64);
-
-                    let _ = query.reply_tx.send(estimation.to_string().as_bytes());
-                }
-                "connected" => {
-                    let connected_request: types::QueryConnectedRequest = serde_json::from_str(&msg.data)?;
-                    let peer_id = connected_request.from;
-
-                    let mut lock = app_state.lock().await;
-                    let probe = (*lock).as_mut().unwrap();
-                    probe.add_pending_peer(peer_id.clone());
-
-                    let _ = query.reply_tx.send(peer_id.clone().as_bytes());
-                }
-                "best_endpoint" => {
-                    let best_endpoint_request: types::QueryBestEndpointRequest = serde_json::from_str(&msg.data)?;
-                    let peer_id = best_endpoint_request.to;
-
-                    let lock = app_state.lock().await;
-                    let probe = (*lock).as_ref().unwrap();
-                    let best_endpoint = probe.get_best_endpoint_to(peer_id.clone()).unwrap();
-
-                    let _ = query.reply_tx.send(best_endpoint.as_bytes());
-                }
-                "status" => {
-                    let lock = app_state.lock().await;
-                    let probe = (*lock).as_ref().unwrap();
-
-                    let status = serde_json::to_string(&probe.status).unwrap();
-                    let _ = query.reply_tx.send(status.as_bytes());
-                }
-                _ => {
-                    info!("Unknown message: {:?}", msg);
-                }
-            }
+            info!("Received host query of {} bytes from: {:?}", query.payload.len(), query.origin);
+            let reply = handle(&app_state, &query.payload).await;
+            let _ = query.reply_tx.send(&reply);
         } else {
             info!("Query channel closed");
         }
     }
 
     // Unreachable code
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scale::Encode;
+
+    #[test]
+    fn tells_json_from_scale() {
+        assert!(is_json(br#"{"command": "status", "data": ""}"#));
+        assert!(is_json(b" \n\t{}"));
+        assert!(!is_json(&protocol::VersionedRequest::V1(protocol::Request::Status).encode()));
+        assert!(!is_json(b""));
+        assert!(!is_json(b"status"));
+    }
+
+    #[test]
+    fn reads_json_queries() {
+        let command = json_command(br#"{"command": "best_endpoint", "data": "{\"to\": \"a\"}"}"#);
+        assert!(matches!(command, Ok(Command::BestEndpoint { to }) if to == "a"));
+        assert!(matches!(json_command(b"{"), Err(Error::BadRequest(_))));
+        assert!(matches!(
+            json_command(br#"{"command": "start_optimize", "data": ""}"#),
+            Err(Error::Forbidden(_))
+        ));
+    }
+}
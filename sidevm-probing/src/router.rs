@@ -1,106 +1,299 @@
-use log::info;
+use log::{info, warn};
 use std::convert::Infallible;
 
+use hyper::body::HttpBody;
 use hyper::{Body, Request, Response};
+use serde::de::DeserializeOwned;
 
 use routerify::prelude::*;
 use routerify::Router;
 
+use crate::command::{dispatch, Command};
+use crate::error::{initialized, initialized_mut, to_json, Error};
+use crate::types::{EchoTimestamps, EstimateBatchRequest, JoinRequest, NearestRequest};
+use crate::utils::now_us;
 use crate::AppState;
 
+// largest JSON body accepted on POST routes
+const MAX_JSON_BODY_BYTES: usize = 1024 * 1024;
+
+fn failure(err: Error) -> Result<Response<Body>, Infallible> {
+    warn!("Request failed: {}", err);
+    Ok(err.into_response())
+}
+
+fn reply<T: Into<Body>>(result: Result<T, Error>) -> Result<Response<Body>, Infallible> {
+    match result {
+        Ok(body) => Ok(Response::new(body.into())),
+        Err(err) => failure(err),
+    }
+}
+
+fn query_param<'a>(req: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    req.uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+}
+
+/// Reads a JSON body of at most `MAX_JSON_BODY_BYTES`, refusing larger ones before buffering them.
+async fn read_json<T: DeserializeOwned>(mut body: Body) -> Result<T, Error> {
+    let too_large = || Error::BadRequest(format!("body must be at most {} bytes", MAX_JSON_BODY_BYTES));
+    if body.size_hint().lower() > MAX_JSON_BODY_BYTES as u64 {
+        return Err(too_large());
+    }
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| Error::BadRequest(err.to_string()))?;
+        if bytes.len() + chunk.len() > MAX_JSON_BODY_BYTES {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    serde_json::from_slice(&bytes).map_err(|err| Error::BadRequest(err.to_string()))
+}
+
+async fn run(req: &Request<Body>, command: Command) -> Result<Response<Body>, Infallible> {
+    let state = req.data::<AppState>().unwrap();
+    reply(dispatch(state, command).await.and_then(|response| response.into_body()))
+}
+
 async fn echo_handler(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let received_at = now_us();
     info!("GET /echo/:msg");
     let msg = req.param("msg").unwrap();
-    Ok(Response::new(Body::from(msg.clone())))
+    // a numeric message is the sender's timestamp, answer with ours for clock estimation
+    let reply = match msg.parse::<u64>() {
+        Ok(sent_at) => serde_json::to_string(&EchoTimestamps {
+            sent_at,
+            received_at,
+            replied_at: now_us(),
+        })
+        .unwrap(),
+        Err(_) => msg.clone(),
+    };
+    Ok(Response::new(Body::from(reply)))
 }
 
-async fn resolved_handler(req: Request<Body>) -> Result<Response<Body>, Infallible> {
-    info!("GET /resolved");
+async fn capabilities_handler(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    info!("GET /capabilities");
     let state = req.data::<AppState>().unwrap();
     let lock = state.lock().await;
-    let probe = (*lock).as_ref().unwrap();
+    reply(initialized(&lock).and_then(|probe| to_json(&probe.capabilities())))
+}
 
-    let resolved = serde_json::to_string(&probe.resolved).unwrap();
-    Ok(Response::new(Body::from(resolved)))
+async fn resolved_handler(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    info!("GET /resolved");
+    run(&req, Command::Resolved).await
 }
 
 async fn estimate_handler(req: Request<Body>) -> Result<Response<Body>, Infallible> {
     info!("GET /estimate/:from/:to");
-    let peer_id_from = req.param("from").unwrap();
-    let peer_id_to = req.param("to").unwrap();
-    let state = req.data::<AppState>().unwrap();
-    let lock = state.lock().await;
-    let probe = (*lock).as_ref().unwrap();
-    let estimation = probe.estimate(peer_id_from.clone(), peer_id_to.clone())
-        .unwrap_or(-1.0 as f64);
+    let from = req.param("from").unwrap().clone();
+    let to = req.param("to").unwrap().clone();
+    run(&req, Command::Estimate { from, to }).await
+}
+
+async fn estimate_batch_handler(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    info!("POST /estimate/batch");
+    let state = req.data::<AppState>().unwrap().clone();
+    let request = match read_json::<EstimateBatchRequest>(req.into_body()).await {
+        Ok(request) => request,
+        Err(err) => return failure(err),
+    };
 
-    Ok(Response::new(Body::from(estimation.to_string())))
+    reply(dispatch(&state, Command::EstimateBatch(request)).await.and_then(|response| response.into_body()))
 }
 
-async fn connected_handler(req: Request<Body>) -> Result<Response<Body>, Infallible> {
-    info!("GET /connected/:from");
-    let peer_id = req.param("from").unwrap();
+async fn estimate_bandwidth_handler(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    info!("GET /estimate_bandwidth/:from/:to");
+    let from = req.param("from").unwrap().clone();
+    let to = req.param("to").unwrap().clone();
+    run(&req, Command::EstimateBandwidth { from, to }).await
+}
+
+async fn payload_handler(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    info!("GET /payload/:bytes");
+    let max_payload_bytes = {
+        let state = req.data::<AppState>().unwrap();
+        let lock = state.lock().await;
+        match initialized(&lock) {
+            Ok(probe) => probe.parameters.max_payload_bytes,
+            Err(err) => return failure(err),
+        }
+    };
+    let bytes = match req.param("bytes").unwrap().parse::<u64>() {
+        Ok(bytes) if bytes <= max_payload_bytes => bytes,
+        _ => {
+            let err = Error::BadRequest(format!("payload size must be at most {} bytes", max_payload_bytes));
+            return Ok(err.into_response());
+        }
+    };
+
+    Ok(Response::new(Body::from(vec![0u8; bytes as usize])))
+}
+
+async fn join_challenge_handler(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    info!("GET /join/challenge");
     let state = req.data::<AppState>().unwrap();
     let mut lock = state.lock().await;
-    let probe = (*lock).as_mut().unwrap();
+    reply(initialized_mut(&mut lock).and_then(|probe| probe.issue_join_challenge().and_then(|challenge| to_json(&challenge))))
+}
+
+async fn join_handler(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    info!("POST /join");
+    let state = req.data::<AppState>().unwrap().clone();
+    let request = match read_json::<JoinRequest>(req.into_body()).await {
+        Ok(request) => request,
+        Err(err) => return failure(err),
+    };
+
+    let (mut peer, parameters) = {
+        let mut lock = state.lock().await;
+        let probe = match initialized_mut(&mut lock) {
+            Ok(probe) => probe,
+            Err(err) => return failure(err),
+        };
+        match probe.verify_join_request(&request) {
+            Ok(peer) => (peer, probe.parameters.clone()),
+            Err(err) => return failure(Error::Forbidden(err.to_string())),
+        }
+    };
+
+    // the advertised endpoints must be reachable and serve data signed by the joining key
+    if let Err(err) = peer.resolved(&parameters).await {
+        return failure(Error::Forbidden(err.to_string()));
+    }
+
+    let mut lock = state.lock().await;
+    let probe = match initialized_mut(&mut lock) {
+        Ok(probe) => probe,
+        Err(err) => return failure(err),
+    };
+    let added = match probe.add_pending_join(peer) {
+        Ok(added) => added,
+        Err(err) => return failure(err),
+    };
+    info!("Peer {} joined (new: {})", &request.public_key, added);
 
-    probe.add_pending_peer(peer_id.clone());
-    Ok(Response::new(Body::from(peer_id.clone())))
+    reply(Ok(request.public_key))
 }
 
 async fn best_endpoint_handler(req: Request<Body>) -> Result<Response<Body>, Infallible> {
     info!("GET /best_endpoint/:to");
-    let peer_id = req.param("to").unwrap();
-    let state = req.data::<AppState>().unwrap();
-    let lock = state.lock().await;
-    let probe = (*lock).as_ref().unwrap();
-
-    let best_endpoint = probe.get_best_endpoint_to(peer_id.clone()).unwrap();
-
-    Ok(Response::new(Body::from(best_endpoint)))
+    let to = req.param("to").unwrap().clone();
+    run(&req, Command::BestEndpoint { to }).await
 }
 
 async fn status_handler(req: Request<Body>) -> Result<Response<Body>, Infallible> {
     info!("GET /status");
-    let state = req.data::<AppState>().unwrap();
-    let lock = state.lock().await;
-    let probe = (*lock).as_ref().unwrap();
-
-    let status = serde_json::to_string(&probe.status).unwrap();
-    Ok(Response::new(Body::from(status)))
+    run(&req, Command::Status).await
 }
 
 async fn telemetry_handler(req: Request<Body>) -> Result<Response<Body>, Infallible> {
     info!("GET /debug/telemetry");
     let state = req.data::<AppState>().unwrap();
     let lock = state.lock().await;
-    let probe = (*lock).as_ref().unwrap();
-
-    let telemetry = serde_json::to_string(&probe.telemetry).unwrap();
-    Ok(Response::new(Body::from(telemetry)))
+    reply(initialized(&lock).and_then(|probe| to_json(&probe.telemetry)))
 }
 
 async fn peers_handler(req: Request<Body>) -> Result<Response<Body>, Infallible> {
     info!("GET /debug/peers");
     let state = req.data::<AppState>().unwrap();
     let lock = state.lock().await;
-    let probe = (*lock).as_ref().unwrap();
+    reply(initialized(&lock).and_then(|probe| to_json(&probe.peers)))
+}
+
+async fn clock_handler(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    info!("GET /debug/clock");
+    let state = req.data::<AppState>().unwrap();
+    let lock = state.lock().await;
+    reply(initialized(&lock).and_then(|probe| to_json(&probe.clock_estimates())))
+}
+
+async fn history_handler(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    info!("GET /debug/history/:peer");
+    let peer = req.param("peer").unwrap().clone();
+    let since = query_param(&req, "since")
+        .and_then(|since| since.parse::<u64>().ok())
+        .unwrap_or(0);
+    run(&req, Command::History { peer, since }).await
+}
+
+fn nearest_k(req: &Request<Body>) -> Result<Option<usize>, Error> {
+    query_param(req, "k")
+        .map(|k| k.parse::<usize>().map_err(|_| Error::BadRequest(format!("invalid k {}", k))))
+        .transpose()
+}
+
+async fn nearest_to_peer_handler(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    info!("GET /nearest/:peer");
+    let peer = req.param("peer").unwrap().clone();
+    let k = match nearest_k(&req) {
+        Ok(k) => k,
+        Err(err) => return failure(err),
+    };
+    run(&req, Command::Nearest(NearestRequest { peer: Some(peer), coord: None, k })).await
+}
+
+async fn nearest_to_coordinate_handler(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    info!("GET /nearest");
+    let k = match nearest_k(&req) {
+        Ok(k) => k,
+        Err(err) => return failure(err),
+    };
+    // comma separated components, possibly percent-encoded
+    let coord = match query_param(&req, "coord")
+        .map(|coord| {
+            coord
+                .replace("%2C", ",")
+                .replace("%2c", ",")
+                .split(',')
+                .map(|x| x.parse::<f64>())
+                .collect::<Result<Vec<f64>, _>>()
+                .map_err(|_| Error::BadRequest(format!("invalid coordinate {}", coord)))
+        })
+        .transpose()
+    {
+        Ok(coord) => coord,
+        Err(err) => return failure(err),
+    };
+    run(&req, Command::Nearest(NearestRequest { peer: None, coord, k })).await
+}
+
+async fn command_handler(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    info!("POST /command");
+    let state = req.data::<AppState>().unwrap().clone();
+    let command = match read_json::<Command>(req.into_body()).await.and_then(Command::read_only) {
+        Ok(command) => command,
+        Err(err) => return failure(err),
+    };
 
-    let peers = serde_json::to_string(&probe.peers).unwrap();
-    Ok(Response::new(Body::from(peers)))
+    reply(dispatch(&state, command).await.and_then(|response| response.into_body()))
 }
 
 pub fn router(app_state: AppState) -> Router<Body, Infallible> {
     Router::builder()
         .data(app_state)
         .get("/echo/:msg", echo_handler)
+        .get("/capabilities", capabilities_handler)
         .get("/resolved", resolved_handler)
         .get("/estimate/:from/:to", estimate_handler)
-        .get("/connected/:from", connected_handler)
+        .post("/estimate/batch", estimate_batch_handler)
+        .get("/estimate_bandwidth/:from/:to", estimate_bandwidth_handler)
+        .get("/payload/:bytes", payload_handler)
+        .get("/join/challenge", join_challenge_handler)
+        .post("/join", join_handler)
         .get("/best_endpoint/:to", best_endpoint_handler)
         .get("/status", status_handler)
         .get("/debug/telemetry", telemetry_handler)
         .get("/debug/peers", peers_handler)
+        .get("/debug/clock", clock_handler)
+        .get("/debug/history/:peer", history_handler)
+        .get("/nearest", nearest_to_coordinate_handler)
+        .get("/nearest/:peer", nearest_to_peer_handler)
+        .post("/command", command_handler)
         .build()
         .unwrap()
 }
@@ -0,0 +1,100 @@
+use serde::{Deserialize, Serialize};
+
+use crate::types::ProbeParameters;
+
+/// When a peer should be probed next. Healthy peers are probed every `probe_interval_ms`, failing ones
+/// with an exponential backoff; both are jittered so that probes spread over the epochs.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ProbeSchedule {
+    // in ms, 0 for a peer that was never probed
+    pub next_probe_at: u64,
+    // current backoff, 0 while the peer answers
+    pub backoff_ms: u64,
+}
+
+fn jittered(interval_ms: u64, parameters: &ProbeParameters) -> u64 {
+    let factor = 1.0 + parameters.probe_jitter * (2.0 * rand::random::<f64>() - 1.0);
+    (interval_ms as f64 * factor).max(0.0) as u64
+}
+
+impl ProbeSchedule {
+    pub fn is_due(&self, now: u64) -> bool {
+        self.next_probe_at <= now
+    }
+
+    pub fn success(&mut self, now: u64, parameters: &ProbeParameters) {
+        self.backoff_ms = 0;
+        self.next_probe_at = now + jittered(parameters.probe_interval_ms, parameters);
+    }
+
+    pub fn failure(&mut self, now: u64, parameters: &ProbeParameters) {
+        self.backoff_ms = match self.backoff_ms {
+            0 => parameters.probe_backoff_base_ms,
+            backoff_ms => backoff_ms.saturating_mul(2),
+        }
+        .min(parameters.probe_backoff_max_ms);
+        self.next_probe_at = now + jittered(self.backoff_ms, parameters);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parameters(probe_jitter: f64) -> ProbeParameters {
+        ProbeParameters {
+            probe_interval_ms: 30_000,
+            probe_jitter,
+            probe_backoff_base_ms: 1_000,
+            probe_backoff_max_ms: 5_000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn is_due_from_the_scheduled_time() {
+        let schedule = ProbeSchedule::default();
+        assert!(schedule.is_due(0));
+
+        let mut schedule = ProbeSchedule::default();
+        schedule.success(1_000, &parameters(0.0));
+        assert_eq!(schedule.next_probe_at, 31_000);
+        assert!(!schedule.is_due(30_999));
+        assert!(schedule.is_due(31_000));
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_max() {
+        let mut schedule = ProbeSchedule::default();
+        let mut backoffs = Vec::new();
+        for _ in 0..5 {
+            schedule.failure(100, &parameters(0.0));
+            assert_eq!(schedule.next_probe_at, 100 + schedule.backoff_ms);
+            backoffs.push(schedule.backoff_ms);
+        }
+        assert_eq!(backoffs, [1_000, 2_000, 4_000, 5_000, 5_000]);
+
+        schedule.success(100, &parameters(0.0));
+        assert_eq!(schedule.backoff_ms, 0);
+        schedule.failure(100, &parameters(0.0));
+        assert_eq!(schedule.backoff_ms, 1_000);
+    }
+
+    #[test]
+    fn jitters_within_bounds() {
+        let parameters = parameters(0.2);
+        let mut delays = Vec::new();
+        for _ in 0..200 {
+            let mut schedule = ProbeSchedule::default();
+            schedule.success(0, &parameters);
+            delays.push(schedule.next_probe_at);
+
+            let mut schedule = ProbeSchedule::default();
+            schedule.failure(0, &parameters);
+            assert!((800..=1_200).contains(&schedule.next_probe_at), "{}", schedule.next_probe_at);
+        }
+        assert!(delays.iter().all(|delay| (24_000..=36_000).contains(delay)));
+        // probes actually spread instead of all landing on the interval
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+    }
+}
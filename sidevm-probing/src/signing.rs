@@ -0,0 +1,40 @@
+use anyhow::{anyhow, Result};
+use once_cell::sync::OnceCell;
+use schnorrkel::{Keypair, PublicKey, SecretKey, Signature};
+
+// Same context as `sp_core::sr25519`, so that signatures interoperate with the pink signing API.
+const SIGNING_CONTEXT: &[u8] = b"substrate";
+
+static KEYPAIR: OnceCell<Keypair> = OnceCell::new();
+
+pub fn keypair_from_secret(secret_key: &[u8]) -> Result<Keypair> {
+    let secret_key = SecretKey::from_bytes(secret_key)
+        .map_err(|err| anyhow!("Invalid sr25519 secret key: {:?}", err))?;
+    Ok(secret_key.to_keypair())
+}
+
+/// Installs the worker key used by `sign`. Can only be called once.
+pub fn init(keypair: Keypair) -> Result<()> {
+    KEYPAIR.set(keypair).map_err(|_| anyhow!("Signing key is already initialized"))
+}
+
+pub fn sign(message: &[u8]) -> Result<Vec<u8>> {
+    let keypair = KEYPAIR.get().ok_or(anyhow!("Signing key is not initialized"))?;
+    Ok(sign_with(keypair, message))
+}
+
+pub fn sign_with(keypair: &Keypair, message: &[u8]) -> Vec<u8> {
+    keypair.sign_simple(SIGNING_CONTEXT, message).to_bytes().to_vec()
+}
+
+pub fn verify(encoded_public_key: &str, message: &[u8], signature: &[u8]) -> Result<()> {
+    let public_key = hex::decode(encoded_public_key)
+        .map_err(|err| anyhow!("Malformed public key {}: {:?}", encoded_public_key, err))?;
+    let public_key = PublicKey::from_bytes(&public_key)
+        .map_err(|err| anyhow!("Invalid sr25519 public key {}: {:?}", encoded_public_key, err))?;
+    let signature = Signature::from_bytes(signature)
+        .map_err(|err| anyhow!("Malformed signature: {:?}", err))?;
+    public_key
+        .verify_simple(SIGNING_CONTEXT, message, &signature)
+        .map_err(|_| anyhow!("Signature does not match {}", encoded_public_key))
+}
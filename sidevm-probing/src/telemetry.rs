@@ -0,0 +1,168 @@
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+
+use crate::types::{BandwidthStats, LinkStats, ProbeParameters};
+use crate::utils::{now_ms, percentile};
+
+impl LinkStats {
+    /// Stats of our link to ourselves, which is always measured and instantaneous.
+    pub fn local(now: u64) -> Self {
+        LinkStats {
+            rtt_ms: Some(0.0),
+            last_seen: now,
+            updated_at: now,
+            ..Default::default()
+        }
+    }
+
+    pub fn record(&mut self, rtt: f64, now: u64, parameters: &ProbeParameters) {
+        self.rtt_ms = Some(match self.rtt_ms {
+            Some(value) => value * parameters.beta + rtt * (1.0 - parameters.beta),
+            None => rtt,
+        });
+        self.samples += 1;
+        self.loss_rate *= parameters.beta;
+        self.last_seen = now;
+        self.updated_at = now;
+
+        if self.recent.len() >= parameters.rtt_window as usize {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(rtt);
+        let recent = self.recent.iter().cloned().collect::<Vec<f64>>();
+        let mean = recent.iter().sum::<f64>() / recent.len() as f64;
+        self.jitter_ms = (recent.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / recent.len() as f64).sqrt();
+        self.min_rtt_ms = percentile(&recent, 0.0);
+        self.p50_rtt_ms = percentile(&recent, 0.5);
+        self.p95_rtt_ms = percentile(&recent, 0.95);
+    }
+
+    pub fn record_failure(&mut self, now: u64, parameters: &ProbeParameters) {
+        self.failures += 1;
+        self.updated_at = now;
+        self.loss_rate = self.loss_rate * parameters.beta + (1.0 - parameters.beta);
+    }
+}
+
+impl BandwidthStats {
+    pub fn record(&mut self, mbps: f64, now: u64, parameters: &ProbeParameters) {
+        self.mbps = match self.samples {
+            0 => mbps,
+            _ => self.mbps * parameters.beta + mbps * (1.0 - parameters.beta),
+        };
+        self.samples += 1;
+        self.last_measured = now;
+    }
+}
+
+/// The smoothed RTT of every measured link, which is what the optimizers fit.
+pub fn rtts(telemetry: &HashMap<String, LinkStats>) -> HashMap<String, f64> {
+    telemetry
+        .iter()
+        .filter_map(|(id, stats)| stats.rtt_ms.map(|rtt| (id.clone(), rtt)))
+        .collect()
+}
+
+// saved states from before link stats kept the smoothed RTT alone
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedLink {
+    Stats(LinkStats),
+    Rtt(f64),
+}
+
+/// Reads `Probe::telemetry`, turning the bare RTTs of older saved states into link stats.
+pub fn deserialize_telemetry<'de, D>(deserializer: D) -> Result<HashMap<String, LinkStats>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let now = now_ms();
+    let links = HashMap::<String, SavedLink>::deserialize(deserializer)?;
+    Ok(links
+        .into_iter()
+        .map(|(id, link)| {
+            let stats = match link {
+                SavedLink::Stats(stats) => stats,
+                SavedLink::Rtt(rtt) => LinkStats {
+                    rtt_ms: Some(rtt),
+                    samples: 1,
+                    last_seen: now,
+                    updated_at: now,
+                    ..Default::default()
+                },
+            };
+            (id, stats)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parameters() -> ProbeParameters {
+        ProbeParameters {
+            beta: 0.5,
+            rtt_window: 4,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn smooths_the_rtt() {
+        let mut stats = LinkStats::default();
+        stats.record(10.0, 1, &parameters());
+        assert_eq!(stats.rtt_ms, Some(10.0));
+        stats.record(20.0, 2, &parameters());
+        assert_eq!(stats.rtt_ms, Some(15.0));
+        assert_eq!(stats.samples, 2);
+        assert_eq!((stats.last_seen, stats.updated_at), (2, 2));
+    }
+
+    #[test]
+    fn keeps_stats_over_the_recent_window() {
+        let mut stats = LinkStats::default();
+        for rtt in 1..=6 {
+            stats.record(rtt as f64, rtt, &parameters());
+        }
+        assert_eq!(stats.recent, [3.0, 4.0, 5.0, 6.0]);
+        assert_eq!((stats.min_rtt_ms, stats.p50_rtt_ms, stats.p95_rtt_ms), (3.0, 5.0, 6.0));
+        assert!((stats.jitter_ms - 1.25f64.sqrt()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn failures_raise_the_loss_rate() {
+        let mut stats = LinkStats::default();
+        stats.record(10.0, 1, &parameters());
+        stats.record_failure(2, &parameters());
+        stats.record_failure(3, &parameters());
+        assert_eq!(stats.failures, 2);
+        assert_eq!(stats.loss_rate, 0.75);
+        assert_eq!((stats.last_seen, stats.updated_at), (1, 3));
+        assert_eq!(stats.rtt_ms, Some(10.0));
+
+        stats.record(10.0, 4, &parameters());
+        assert_eq!(stats.loss_rate, 0.375);
+    }
+
+    #[derive(Deserialize)]
+    struct Saved {
+        #[serde(deserialize_with = "deserialize_telemetry")]
+        telemetry: HashMap<String, LinkStats>,
+    }
+
+    #[test]
+    fn reads_bare_rtts_of_older_states() {
+        let mut stats = LinkStats::default();
+        stats.record(20.0, 1, &parameters());
+        let json = format!(r#"{{"telemetry": {{"old": 12.5, "new": {}}}}}"#, serde_json::to_string(&stats).unwrap());
+        let saved = serde_json::from_str::<Saved>(&json).unwrap();
+
+        let old = &saved.telemetry["old"];
+        assert_eq!((old.rtt_ms, old.samples, old.failures), (Some(12.5), 1, 0));
+        assert!(old.last_seen > 0 && old.updated_at == old.last_seen);
+        let new = &saved.telemetry["new"];
+        assert_eq!((new.rtt_ms, new.samples, new.last_seen), (Some(20.0), 1, 1));
+        assert_eq!(new.recent, [20.0]);
+    }
+}
@@ -1,6 +1,123 @@
+use scale::Encode;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
 
+use crate::error::ErrorReply;
+use crate::distance::{DistanceModel, DotProduct, Euclidean, EuclideanHeight, Hyperbolic, Spherical};
+use crate::utils::gen_random_vec;
+
+/// How the views of a coordinate fetched from several peers are combined.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Aggregator {
+    Mean,
+    Median,
+    TrimmedMean,
+    Krum,
+}
+
+impl Default for Aggregator {
+    fn default() -> Self {
+        Aggregator::Median
+    }
+}
+
+impl Aggregator {
+    pub fn from_code(code: u8) -> Option<Aggregator> {
+        match code {
+            0 => Some(Aggregator::Mean),
+            1 => Some(Aggregator::Median),
+            2 => Some(Aggregator::TrimmedMean),
+            3 => Some(Aggregator::Krum),
+            _ => None,
+        }
+    }
+}
+
+/// How our own coordinate is fitted to the measured latencies.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Encode)]
+pub enum OptimizeMode {
+    // plain Euclidean vectors fitted with momentum gradient descent
+    GradientDescent,
+    // Euclidean vectors plus a height, fitted with Vivaldi's adaptive timestep
+    Vivaldi,
+    // separate outgoing and incoming vectors for asymmetric latencies (DMF)
+    MatrixFactorization,
+}
+
+impl Default for OptimizeMode {
+    fn default() -> Self {
+        OptimizeMode::GradientDescent
+    }
+}
+
+impl OptimizeMode {
+    pub fn from_code(code: u8) -> Option<OptimizeMode> {
+        match code {
+            0 => Some(OptimizeMode::GradientDescent),
+            1 => Some(OptimizeMode::Vivaldi),
+            2 => Some(OptimizeMode::MatrixFactorization),
+            _ => None,
+        }
+    }
+}
+
+/// The space `OptimizeMode::GradientDescent` embeds nodes in.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Encode)]
+pub enum DistanceModelKind {
+    Euclidean,
+    EuclideanHeight,
+    Spherical,
+    Hyperbolic,
+}
+
+impl Default for DistanceModelKind {
+    fn default() -> Self {
+        DistanceModelKind::Euclidean
+    }
+}
+
+impl DistanceModelKind {
+    pub fn from_code(code: u8) -> Option<DistanceModelKind> {
+        match code {
+            0 => Some(DistanceModelKind::Euclidean),
+            1 => Some(DistanceModelKind::EuclideanHeight),
+            2 => Some(DistanceModelKind::Spherical),
+            3 => Some(DistanceModelKind::Hyperbolic),
+            _ => None,
+        }
+    }
+}
+
+/// How the RTT samples of a probe are reduced to a single measurement.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum RttFilter {
+    Min,
+    Median,
+    // `rtt_percentile` over the last `rtt_window` samples of the peer
+    MovingPercentile,
+}
+
+impl Default for RttFilter {
+    fn default() -> Self {
+        RttFilter::Median
+    }
+}
+
+impl RttFilter {
+    pub fn from_code(code: u8) -> Option<RttFilter> {
+        match code {
+            0 => Some(RttFilter::Min),
+            1 => Some(RttFilter::Median),
+            2 => Some(RttFilter::MovingPercentile),
+            _ => None,
+        }
+    }
+}
+
+// parameters missing from a saved state, such as ones added since, take their configured value
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default = "ProbeParameters::load")]
 pub struct ProbeParameters {
     pub dim_size: u64,
     pub sample_size: u64,
@@ -12,16 +129,358 @@ pub struct ProbeParameters {
     pub factor: f64,
     pub min_lr: f64,
     pub max_iters: u64,
-    pub max_offline_cnt: u8,
+    pub aggregator: Aggregator,
+    // fraction of contributions dropped at each end by `Aggregator::TrimmedMean`
+    pub trim_ratio: f64,
+    // number of byzantine contributions tolerated by `Aggregator::Krum`
+    pub krum_byzantine: u64,
+    pub optimize_mode: OptimizeMode,
+    // Vivaldi timestep and error smoothing constants
+    pub vivaldi_cc: f64,
+    pub vivaldi_ce: f64,
+    pub distance_model: DistanceModelKind,
+    // radius of `DistanceModelKind::Spherical`, in ms
+    pub sphere_radius: f64,
+    // distance scale of `DistanceModelKind::Hyperbolic`, in ms; gradient steps are scaled down to the unit
+    // ball accordingly, so `lr` keeps the same meaning as in the other spaces
+    pub hyperbolic_scale: f64,
+    // learning rate and regularisation of `OptimizeMode::MatrixFactorization`
+    pub mf_lr: f64,
+    pub mf_lambda: f64,
+    // echoes sent to every endpoint per probe
+    pub probe_samples: u64,
+    pub rtt_filter: RttFilter,
+    pub rtt_percentile: f64,
+    pub rtt_window: u64,
+    // deadline of a single echo, and of all the probes of an epoch
+    pub probe_timeout_ms: u64,
+    pub epoch_probe_budget_ms: u64,
+    // outgoing HTTP requests give up connecting after `http_connect_timeout_ms`, and on the response
+    // head after `http_response_timeout_ms`, connection setup included
+    pub http_connect_timeout_ms: u64,
+    pub http_response_timeout_ms: u64,
+    pub http_read_timeout_ms: u64,
+    pub http_max_retries: u64,
+    pub http_retry_backoff_ms: u64,
+    // the TCP echo listens on the HTTP port plus this offset, 0 disables it
+    pub tcp_echo_port_offset: u16,
+    // echo connections served at once, further ones are closed right away
+    pub tcp_echo_max_connections: u64,
+    // echo samples kept per peer for clock estimation
+    pub clock_window: u64,
+    // peers whose link loses more probes than this are not offered as a destination
+    pub max_loss_rate: f64,
+    // a bandwidth probe of `bandwidth_probe_peers` peers runs every `bandwidth_probe_interval`
+    // epochs (0 disables it), each downloading `bandwidth_probe_bytes`
+    pub bandwidth_probe_interval: u64,
+    pub bandwidth_probe_peers: u64,
+    pub bandwidth_probe_bytes: u64,
+    // largest payload served on `/payload/:bytes`
+    pub max_payload_bytes: u64,
+    // most pairs estimated by one `estimate_batch`
+    pub max_batch_pairs: u64,
+    // samples kept in the history of every link
+    pub history_capacity: u64,
+    // links that did not answer and resolved entries not refreshed for this long are dropped
+    pub telemetry_ttl_ms: u64,
+    pub resolved_ttl_ms: u64,
+    // suspicion levels from which a peer is suspected and declared offline by the failure detector,
+    // which looks at the last `phi_window` intervals between successful probes
+    pub phi_suspect: f64,
+    pub phi_offline: f64,
+    pub phi_window: u64,
+    pub phi_min_std_ms: f64,
+    // peers held offline by the failure detector for this long are evicted
+    pub offline_evict_ms: u64,
+    // healthy peers are probed every `probe_interval_ms` (+/- `probe_jitter` of it), failing ones after
+    // a backoff doubling from `probe_backoff_base_ms` up to `probe_backoff_max_ms`
+    pub probe_interval_ms: u64,
+    pub probe_jitter: f64,
+    pub probe_backoff_base_ms: u64,
+    pub probe_backoff_max_ms: u64,
+    // peers probed per epoch, among which at most `detection_size` offline ones
+    pub max_probes_per_epoch: u64,
 
     pub eps: f64,
 }
 
+impl ProbeParameters {
+    /// The space coordinates live in. Vivaldi always uses Euclidean positions with heights, and
+    /// matrix factorisation its outgoing/incoming vectors.
+    pub fn distance_model(&self) -> Box<dyn DistanceModel> {
+        match self.optimize_mode {
+            OptimizeMode::Vivaldi => return Box::new(EuclideanHeight),
+            OptimizeMode::MatrixFactorization => return Box::new(DotProduct),
+            OptimizeMode::GradientDescent => {}
+        }
+        match self.distance_model {
+            DistanceModelKind::Euclidean => Box::new(Euclidean),
+            DistanceModelKind::EuclideanHeight => Box::new(EuclideanHeight),
+            DistanceModelKind::Spherical => Box::new(Spherical {
+                radius: self.sphere_radius,
+            }),
+            DistanceModelKind::Hyperbolic => Box::new(Hyperbolic {
+                scale: self.hyperbolic_scale,
+            }),
+        }
+    }
+
+    /// Length of a coordinate in `Probe::resolved`.
+    pub fn vector_len(&self) -> usize {
+        self.distance_model().vector_len(self.dim_size as usize)
+    }
+
+    /// A random initial coordinate inside the valid region of the space.
+    pub fn random_coordinate(&self) -> Vec<f64> {
+        let mut coordinate = gen_random_vec::<f64>(self.vector_len());
+        self.distance_model().project(&mut coordinate);
+        coordinate
+    }
+
+    /// Digest of the parameters that must agree between two peers for their maps to be mergeable.
+    pub fn fingerprint(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.dim_size.encode());
+        hasher.update(self.optimize_mode.encode());
+        hasher.update(self.distance_model.encode());
+        hasher.update(self.sphere_radius.to_bits().encode());
+        hasher.update(self.hyperbolic_scale.to_bits().encode());
+        hex::encode(hasher.finalize())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct ProbeStatus {
     pub is_optimizing: bool,
     pub precision_ms: f64,
     pub epoch: u64,
+    // contributions discarded by the aggregator during the last epoch
+    #[serde(default)]
+    pub rejected_contributions: u64,
+    // peer maps skipped during the last epoch because they could not be aligned onto ours
+    #[serde(default)]
+    pub misaligned_maps: u64,
+    // peers evicted, and telemetry and resolved entries garbage collected, during the last epoch
+    #[serde(default)]
+    pub removed_peers: u64,
+    #[serde(default)]
+    pub removed_telemetry: u64,
+    #[serde(default)]
+    pub removed_resolved: u64,
+}
+
+/// Response of `/resolved`: the node's map together with a signature over `signing_payload`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SignedResolved {
+    pub epoch: u64,
+    // signing time in ms; unlike the epoch it keeps growing across restarts of the node
+    #[serde(default)]
+    pub timestamp: u64,
+    pub public_key: String,
+    pub resolved: HashMap<String, Vec<f64>>,
+    // the node's own error estimate, used to weight Vivaldi updates
+    pub error: f64,
+    // the node's own measurements, which tell each peer the latency in its incoming direction
+    pub telemetry: HashMap<String, f64>,
+    pub signature: String,
+}
+
+impl SignedResolved {
+    /// SCALE encoding of `(epoch, timestamp, public_key, entries, error, telemetry)` with entries sorted by peer id and
+    /// numbers as raw `f64` bits, so that both sides derive the same bytes regardless of JSON formatting.
+    pub fn signing_payload(&self) -> Vec<u8> {
+        let mut entries = self
+            .resolved
+            .iter()
+            .map(|(k, v)| (k.clone(), v.iter().map(|x| x.to_bits()).collect::<Vec<u64>>()))
+            .collect::<Vec<(String, Vec<u64>)>>();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut telemetry = self
+            .telemetry
+            .iter()
+            .map(|(k, v)| (k.clone(), v.to_bits()))
+            .collect::<Vec<(String, u64)>>();
+        telemetry.sort_by(|a, b| a.0.cmp(&b.0));
+
+        (self.epoch, self.timestamp, &self.public_key, entries, self.error.to_bits(), telemetry).encode()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Estimate {
+    pub rtt_ms: f64,
+    // only available for models that track their own error, e.g. Vivaldi
+    pub confidence: Option<f64>,
+    // only available when one side is us and the link to the other side has been probed
+    #[serde(default)]
+    pub loss_rate: Option<f64>,
+    #[serde(default)]
+    pub jitter_ms: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EstimatePair {
+    pub from: String,
+    pub to: String,
+}
+
+/// Estimates of every pair in `pairs`, then of `from` to each of `targets`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EstimateBatchRequest {
+    #[serde(default)]
+    pub pairs: Vec<EstimatePair>,
+    #[serde(default)]
+    pub from: Option<String>,
+    #[serde(default)]
+    pub targets: Vec<String>,
+}
+
+/// One pair of a batch, with either its estimate or the reason it has none.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EstimateBatchEntry {
+    pub from: String,
+    pub to: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub estimate: Option<Estimate>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorReply>,
+}
+
+/// Peers closest to either a peer or an arbitrary coordinate.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NearestRequest {
+    #[serde(default)]
+    pub peer: Option<String>,
+    #[serde(default)]
+    pub coord: Option<Vec<f64>>,
+    // defaults to `NEAREST_DEFAULT_K`
+    #[serde(default)]
+    pub k: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Neighbor {
+    pub id: String,
+    // predicted RTT from the query point
+    pub rtt_ms: f64,
+}
+
+/// Throughput of our link from a peer, measured by downloading its `/payload/:bytes`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BandwidthStats {
+    // smoothed throughput in Mbit/s
+    pub mbps: f64,
+    pub samples: u64,
+    // time of the last measurement, in ms
+    pub last_measured: u64,
+}
+
+/// Probe results over a period, in ms: the first sample is at `timestamp`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HistorySample {
+    pub timestamp: u64,
+    // mean RTT of the successful probes, if any
+    pub rtt_ms: Option<f64>,
+    pub samples: u32,
+    pub failures: u32,
+}
+
+/// Bounded, downsampled series of the raw (unsmoothed) probe results of a link.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct History {
+    pub samples: VecDeque<HistorySample>,
+}
+
+/// What we know about our link towards a peer.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LinkStats {
+    // smoothed RTT, `None` until a probe succeeded
+    pub rtt_ms: Option<f64>,
+    // number of successful and of failed probes
+    pub samples: u64,
+    pub failures: u64,
+    // smoothed share of failed probes
+    pub loss_rate: f64,
+    // standard deviation of the recent RTTs
+    pub jitter_ms: f64,
+    pub min_rtt_ms: f64,
+    pub p50_rtt_ms: f64,
+    pub p95_rtt_ms: f64,
+    // time of the last successful probe, in ms
+    pub last_seen: u64,
+    // time of the last probe, successful or not
+    #[serde(default)]
+    pub updated_at: u64,
+    // last `rtt_window` RTTs the stats above are computed from
+    #[serde(default)]
+    pub recent: VecDeque<f64>,
+}
+
+/// Replied by `/echo/:msg` when the message is our send timestamp, all in microseconds.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EchoTimestamps {
+    pub sent_at: u64,
+    pub received_at: u64,
+    pub replied_at: u64,
+}
+
+/// Clock comparison from a single echo. The raw one-way delays include the clock offset.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct ClockSample {
+    // peer clock minus ours
+    pub offset_ms: f64,
+    pub delay_ms: f64,
+    pub forward_ms: f64,
+    pub backward_ms: f64,
+}
+
+/// Clock offset of a peer relative to ours and the one-way delays to (forward) and from (backward) it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ClockEstimate {
+    pub offset_ms: f64,
+    pub delay_ms: f64,
+    pub forward_ms: f64,
+    pub backward_ms: f64,
+    pub samples: u64,
+    pub updated_at: u64,
+}
+
+/// Optional protocols a node supports, served on `/capabilities`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Capabilities {
+    // 0 when the node has no TCP echo
+    #[serde(default)]
+    pub tcp_echo_port_offset: u16,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct JoinChallenge {
+    pub nonce: String,
+}
+
+/// Sent to `POST /join` by a node that wants to become our peer, answering a `JoinChallenge`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct JoinRequest {
+    pub public_key: String,
+    pub endpoints: Vec<String>,
+    pub fingerprint: String,
+    pub nonce: String,
+    pub signature: String,
+}
+
+impl JoinRequest {
+    /// Binds the signature to the challenged node, so a request cannot be replayed against another one.
+    pub fn signing_payload(&self, target_public_key: &str) -> Vec<u8> {
+        (
+            &self.nonce,
+            target_public_key,
+            &self.public_key,
+            &self.endpoints,
+            &self.fingerprint,
+        )
+            .encode()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
@@ -30,6 +489,12 @@ pub struct HostMessage {
     pub data: String,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HostDirectoryEntry {
+    pub id: String,
+    pub endpoints: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct QueryMessage {
     pub command: String,
@@ -43,11 +508,18 @@ pub struct QueryEstimateRequest {
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
-pub struct QueryConnectedRequest {
-    pub from: String,
+pub struct QueryBestEndpointRequest {
+    pub to: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
-pub struct QueryBestEndpointRequest {
-    pub to: String,
+pub struct QueryHistoryRequest {
+    pub peer: String,
+    #[serde(default)]
+    pub since: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct QueryClockOffsetRequest {
+    pub peer: String,
 }
@@ -1,11 +1,9 @@
-use anyhow::Result;
-use hyper::body::Buf;
-use log::info;
+use anyhow::{anyhow, Result};
 use rand::distributions::Standard;
 use rand::prelude::Distribution;
 use scale::Decode;
-use sidevm::net::HttpConnector;
-use std::io::Read;
+use std::future::Future;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub fn cache_get<T>(key: &[u8]) -> Option<T>
 where
@@ -29,6 +27,39 @@ where
     vec
 }
 
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64
+}
+
+pub fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_micros() as u64
+}
+
+/// Runs `future` to completion unless `timeout` elapses first.
+pub async fn with_timeout<F: Future>(timeout: Duration, future: F) -> Result<F::Output> {
+    tokio::select! {
+        output = future => Ok(output),
+        _ = sidevm::time::sleep(timeout) => Err(anyhow!("Timed out after {:?}", timeout)),
+    }
+}
+
+/// Nearest-rank percentile of `samples`, with `p` in `[0, 1]`.
+pub fn percentile(samples: &[f64], p: f64) -> f64 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    let mut sorted = samples.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let rank = (p.clamp(0.0, 1.0) * (sorted.len() - 1) as f64).round() as usize;
+    sorted[rank]
+}
+
 pub fn euclidean_distance(a: &[f64], b: &[f64]) -> f64 {
     let mut sum = 0.0;
     for (i, j) in a.iter().zip(b.iter()) {
@@ -37,37 +68,25 @@ pub fn euclidean_distance(a: &[f64], b: &[f64]) -> f64 {
     sum.sqrt()
 }
 
-// TODO: replace
-pub async fn get_address_by_id(peer_id: &str) -> Result<Vec<String>> {
-    let endpoints = match peer_id {
-        "00000000" => vec!("127.0.0.1:2000".to_string()),
-        "00000001" => vec!("127.0.0.1:2001".to_string()),
-        "00000002" => vec!("127.0.0.1:2002".to_string()),
-        "00000003" => vec!("127.0.0.1:2003".to_string()),
-        "00000004" => vec!("127.0.0.1:2004".to_string()),
-        "00000005" => vec!("127.0.0.1:2005".to_string()),
-        "00000006" => vec!("127.0.0.1:2006".to_string()),
-        "00000007" => vec!("127.0.0.1:2007".to_string()),
-        _ => panic!("Unknown peer id"),
-    };
-
-    Ok(endpoints)
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-pub async fn http_get(url: &str) -> Result<Vec<u8>> {
-    info!("Connecting to {}", url);
-    let connector = HttpConnector::new();
-    let client = hyper::Client::builder()
-        .executor(sidevm::exec::HyperExecutor)
-        .build::<_, String>(connector);
-    let response = client.get(url.parse().expect("Bad url")).await?;
-    info!("response status: {}", response.status());
-
-    let mut buf = vec![];
-    hyper::body::aggregate(response)
-        .await?
-        .reader()
-        .read_to_end(&mut buf)?;
+    #[test]
+    fn percentile_picks_the_nearest_rank() {
+        let samples = [5.0, 1.0, 4.0, 2.0, 3.0];
+        assert_eq!(percentile(&samples, 0.0), 1.0);
+        assert_eq!(percentile(&samples, 0.25), 2.0);
+        assert_eq!(percentile(&samples, 0.5), 3.0);
+        assert_eq!(percentile(&samples, 0.9), 5.0);
+        assert_eq!(percentile(&samples, 1.0), 5.0);
+    }
 
-    Ok(buf)
+    #[test]
+    fn percentile_clamps_and_handles_small_inputs() {
+        assert_eq!(percentile(&[], 0.5), 0.0);
+        assert_eq!(percentile(&[7.0], 0.95), 7.0);
+        assert_eq!(percentile(&[1.0, 2.0], -1.0), 1.0);
+        assert_eq!(percentile(&[1.0, 2.0], 2.0), 2.0);
+    }
 }
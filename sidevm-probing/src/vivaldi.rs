@@ -0,0 +1,60 @@
+use crate::distance::{DistanceModel, EuclideanHeight};
+use crate::types::ProbeParameters;
+use crate::utils::{euclidean_distance, gen_random_vec};
+
+// Vivaldi coordinates are laid out as `[position..., height]`, where the height models the access link.
+
+fn split(coordinate: &[f64]) -> (&[f64], f64) {
+    let (position, height) = coordinate.split_at(coordinate.len().saturating_sub(1));
+    (position, height.first().cloned().unwrap_or(0.0))
+}
+
+/// Moves `me` towards or away from `peer` according to the measured `rtt`, using the adaptive
+/// timestep weighted by both nodes' local error estimates. Updates `my_error` in place.
+pub fn update(
+    me: &mut Vec<f64>,
+    my_error: &mut f64,
+    peer: &[f64],
+    peer_error: f64,
+    rtt: f64,
+    parameters: &ProbeParameters,
+) {
+    if rtt <= 0.0 || me.len() != peer.len() {
+        return;
+    }
+
+    let prediction = EuclideanHeight.distance(me, peer);
+    let weight = *my_error / (*my_error + peer_error + parameters.eps);
+    let sample_error = (prediction - rtt).abs() / rtt;
+    *my_error = (sample_error * parameters.vivaldi_ce * weight
+        + *my_error * (1.0 - parameters.vivaldi_ce * weight))
+        .max(parameters.eps);
+    let timestep = parameters.vivaldi_cc * weight;
+
+    // unit vector pointing from the peer to us; heights always add up
+    let (my_position, my_height) = split(me);
+    let (peer_position, peer_height) = split(peer);
+    let mut direction = my_position
+        .iter()
+        .zip(peer_position.iter())
+        .map(|(i, j)| i - j)
+        .collect::<Vec<f64>>();
+    if euclidean_distance(my_position, peer_position) < parameters.eps {
+        // pick a random direction when both positions coincide
+        direction = gen_random_vec::<f64>(direction.len()).iter().map(|x| x - 0.5).collect();
+    }
+    let position_norm = direction.iter().fold(0.0, |acc, x| acc + x.powi(2)).sqrt();
+    let norm = position_norm + my_height + peer_height + parameters.eps;
+
+    let force = timestep * (rtt - prediction);
+    let dim = direction.len();
+    for (i, x) in direction.iter().enumerate() {
+        me[i] += force * x / norm;
+    }
+    me[dim] = (my_height + force * (my_height + peer_height) / norm).max(0.0);
+}
+
+/// Confidence in `[0, 1]` of a prediction between two nodes with the given error estimates.
+pub fn confidence(error_a: f64, error_b: f64) -> f64 {
+    (1.0 - (error_a + error_b) / 2.0).clamp(0.0, 1.0)
+}